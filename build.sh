@@ -1,4 +1,5 @@
 cargo install --path . --root ./install
 cp git-feature ./install/bin/
 cp git-hotfix ./install/bin/
+cp git-release ./install/bin/
 cp git-changelog ./install/bin/
@@ -0,0 +1,193 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::error;
+use crate::error::Context as _;
+
+//------------------------------------------------------------------------------
+/// How a branch's history relates to the base it forks from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ancestry {
+    /// Commits on the branch that aren't in the base
+    pub ahead: usize,
+    /// Commits in the base that the branch doesn't have yet
+    pub behind: usize,
+    /// The most recent commit they share, None if their histories are
+    /// unrelated
+    pub merge_base: Option<git2::Oid>,
+    /// The base is somewhere in the branch's history, merges included, so
+    /// there's nothing to rebase onto
+    pub base_reachable: bool,
+}
+
+//------------------------------------------------------------------------------
+/// Compare branch with base, both of which have to be in the repo.
+pub fn compare(
+    repo: &git2::Repository,
+    branch: git2::Oid,
+    base: git2::Oid,
+) -> error::Result<Ancestry> {
+    let merge_base = merge_base(repo, branch, base)?;
+
+    Ok(Ancestry {
+        ahead: count(repo, branch, base)?,
+        behind: count(repo, base, branch)?,
+        merge_base,
+        base_reachable: merge_base == Some(base),
+    })
+}
+
+//------------------------------------------------------------------------------
+fn merge_base(
+    repo: &git2::Repository,
+    lhs: git2::Oid,
+    rhs: git2::Oid,
+) -> error::Result<Option<git2::Oid>> {
+    match repo.merge_base(lhs, rhs) {
+        Ok(oid) => Ok(Some(oid)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e).git_error("Unable to find the merge base"),
+    }
+}
+
+//------------------------------------------------------------------------------
+/// The commits reachable from tip but not from hide. The walk visits each
+/// commit once, however much the history branches and merges.
+fn count(
+    repo: &git2::Repository,
+    tip: git2::Oid,
+    hide: git2::Oid,
+) -> error::Result<usize> {
+    let mut walk = repo.revwalk().git_error("Unable to walk the history")?;
+    walk.push(tip).git_error("Unable to walk the history")?;
+    walk.hide(hide).git_error("Unable to walk the history")?;
+
+    let mut count = 0;
+    for oid in walk {
+        oid.git_error("Unable to walk the history")?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bare repo that's removed once the test is done with it.
+    struct Scratch {
+        path: std::path::PathBuf,
+        repo: git2::Repository,
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn scratch(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "git-me-ancestry-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        let repo = git2::Repository::init_bare(&path).unwrap();
+        Scratch { path, repo }
+    }
+
+    /// An empty commit, only the history matters here.
+    fn commit(
+        repo: &git2::Repository,
+        parents: &[git2::Oid],
+        message: &str,
+    ) -> git2::Oid {
+        let tree = repo.treebuilder(None).unwrap().write().unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+        let parents: std::vec::Vec<git2::Commit> = parents
+            .iter()
+            .map(|oid| repo.find_commit(*oid).unwrap())
+            .collect();
+        let parents: std::vec::Vec<&git2::Commit> = parents.iter().collect();
+        let signature = git2::Signature::now("t", "t@example.com").unwrap();
+        repo.commit(None, &signature, &signature, message, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn behind_and_ahead() {
+        let s = scratch("diverged");
+        let fork = commit(&s.repo, &[], "fork");
+        let base = commit(&s.repo, &[fork], "base 1");
+        let base = commit(&s.repo, &[base], "base 2");
+        let branch = commit(&s.repo, &[fork], "branch 1");
+        let branch = commit(&s.repo, &[branch], "branch 2");
+        let branch = commit(&s.repo, &[branch], "branch 3");
+
+        assert_eq!(
+            compare(&s.repo, branch, base).unwrap(),
+            Ancestry {
+                ahead: 3,
+                behind: 2,
+                merge_base: Some(fork),
+                base_reachable: false,
+            }
+        );
+    }
+
+    #[test]
+    fn base_merged_in_through_the_second_parent() {
+        let s = scratch("merged");
+        let fork = commit(&s.repo, &[], "fork");
+        let base = commit(&s.repo, &[fork], "base");
+        let branch = commit(&s.repo, &[fork], "branch");
+        let merge = commit(&s.repo, &[branch, base], "merge base in");
+
+        assert_eq!(
+            compare(&s.repo, merge, base).unwrap(),
+            Ancestry {
+                ahead: 2,
+                behind: 0,
+                merge_base: Some(base),
+                base_reachable: true,
+            }
+        );
+    }
+
+    #[test]
+    fn unrelated_histories() {
+        let s = scratch("unrelated");
+        let base = commit(&s.repo, &[], "base");
+        let branch = commit(&s.repo, &[], "branch 1");
+        let branch = commit(&s.repo, &[branch], "branch 2");
+
+        assert_eq!(
+            compare(&s.repo, branch, base).unwrap(),
+            Ancestry {
+                ahead: 2,
+                behind: 1,
+                merge_base: None,
+                base_reachable: false,
+            }
+        );
+    }
+
+    #[test]
+    fn merge_heavy_history_counts_each_commit_once() {
+        // A chain of diamonds has 2^n paths through it, but 3n commits
+        let s = scratch("diamonds");
+        let base = commit(&s.repo, &[], "base");
+        let mut tip = base;
+        for i in 0..64 {
+            let lhs = commit(&s.repo, &[tip], &format!("lhs {}", i));
+            let rhs = commit(&s.repo, &[tip], &format!("rhs {}", i));
+            tip = commit(&s.repo, &[lhs, rhs], &format!("merge {}", i));
+        }
+
+        let ancestry = compare(&s.repo, tip, base).unwrap();
+        assert_eq!(ancestry.ahead, 3 * 64);
+        assert_eq!(ancestry.behind, 0);
+        assert!(ancestry.base_reachable);
+    }
+}
@@ -9,15 +9,28 @@ use argh::FromArgs;
 ///
 /// Typical workflow:
 /// - git feature start -n my_cool_feature                                   # Start a new feature branch
+///   or git feature start --issue 123                                        # Start from an issue, named after its title
 ///  - ...                                                                    # Your normal git commands
 ///  - git changelog edit --commit/-c                                         # Edit your changelog in vim
-///  - git feature review                                                     # Dry-run, check you're ready to review
+///  - git feature review                                                     # Rebase and push, check you're ready to review
 ///  - git feature review --finished/-f <your reviewer username in gitlab>    # Your done,
+///  - git feature finish                                                     # Merge once approved
+///  - git me sync                                                            # Catch up, and tidy away merged branches
 ///
 /// When you run git feature review, that will remove WIP from your MR and
 /// send a message to the teams merge request channel.
+///
+/// Put --dry-run before any of these, ie git me --dry-run feature finish, to
+/// see what would be done to your branches, the remote and gitlab without
+/// changing anything.
+///
+/// Exit codes: 2 something for you to sort out first, 3 git, 4 auth,
+/// 5 gitlab, 6 config, 7 changelog.
 //
 pub struct Tasks {
+    #[argh(switch)]
+    /// show what would be done, without doing it
+    pub dry_run: bool,
     #[argh(subcommand)]
     pub task: Task,
 }
@@ -28,7 +41,13 @@ pub struct Tasks {
 pub enum Task {
     Feature(Feature),
     Hotfix(Hotfix),
+    Release(Release),
+    Work(Work),
     Review(Review),
+    Status(Overview),
+    Sync(Synchronise),
+    Worktree(Worktree),
+    Hooks(Hooks),
     Changelog(Changelog),
     Setup(Setup),
     Info(Info),
@@ -42,9 +61,66 @@ pub enum Task {
 pub struct Start {
     #[argh(option, short = 'n')]
     /// name of the new feature/hotfix
+    pub name: Option<std::string::String>,
+    #[argh(option)]
+    /// start from a gitlab issue, named after its title unless -n is given.
+    /// The title goes in the changelog, unless the type has a changelog
+    /// template in .git-me.yml, which takes precedence
+    pub issue: Option<u64>,
+    #[argh(option)]
+    /// stack on top of another branch, ie feature/a, rather than the base
+    pub on: Option<std::string::String>,
+    #[argh(switch)]
+    /// stash uncommited changes and bring them over to the new branch
+    pub autostash: bool,
+    #[argh(switch)]
+    /// start in a new worktree next to this one, leaving this checkout alone
+    pub worktree: bool,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// rename the current branch, moving its changelog and merge request over
+#[argh(subcommand, name = "rename")]
+pub struct Rename {
+    #[argh(option, short = 'n')]
+    /// new name of the feature/hotfix
     pub name: std::string::String,
 }
 
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// give up on the current branch, closing its merge request and deleting it
+#[argh(subcommand, name = "abandon")]
+pub struct Abandon {
+    #[argh(option, short = 'm')]
+    /// why, this is left on the merge request as it's closed
+    pub comment: Option<std::string::String>,
+    #[argh(switch, short = 'y')]
+    /// don't ask before deleting the branch
+    pub yes: bool,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// pick up a branch someone else started, and track it
+#[argh(subcommand, name = "checkout")]
+pub struct Checkout {
+    #[argh(positional)]
+    /// name of the feature/hotfix, or the number of its merge request, ie !12
+    pub name: std::string::String,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// copy the branch onto maintained release branches, with a merge request each
+#[argh(subcommand, name = "backport")]
+pub struct Backport {
+    #[argh(option)]
+    /// branch to backport onto, ie release/1.4, can be given more than once
+    pub to: std::vec::Vec<std::string::String>,
+}
+
 //------------------------------------------------------------------------------
 #[derive(FromArgs, PartialEq, Debug)]
 /// review
@@ -53,20 +129,171 @@ pub struct Review {
     #[argh(option, short = 'f')]
     /// is this the final code review?
     pub finished: std::vec::Vec<std::string::String>,
+    #[argh(switch)]
+    /// stash uncommited changes while rebasing, and restore them after
+    pub autostash: bool,
+    #[argh(switch)]
+    /// fold fixup! and squash! commits into the commits they fix, then force
+    /// push with lease
+    pub autosquash: bool,
+    #[argh(switch)]
+    /// squash the 'update changelog' commits into one, then force push with
+    /// lease
+    pub squash_changelog: bool,
+    #[argh(switch)]
+    /// remove WIP even though some commit messages don't follow the rules
+    pub override_rules: bool,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show where the current branch is at
+#[argh(subcommand, name = "status")]
+pub struct Overview {}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// Fetch, bring develop and master up to date, and delete merged branches
+#[argh(subcommand, name = "sync")]
+pub struct Synchronise {
+    #[argh(switch, short = 'y')]
+    /// delete merged and closed branches without asking
+    pub yes: bool,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// list the worktrees
+#[argh(subcommand, name = "list")]
+pub struct WorktreeList {}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// remove a worktree, the branch is kept
+#[argh(subcommand, name = "remove")]
+pub struct WorktreeRemove {
+    #[argh(positional)]
+    /// name of the feature/hotfix, or of the worktree
+    pub name: std::string::String,
+    #[argh(switch)]
+    /// remove it even if there are uncommited changes
+    pub force: bool,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum WorktreeCommand {
+    List(WorktreeList),
+    Remove(WorktreeRemove),
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// Working with worktrees made by 'start --worktree'
+#[argh(subcommand, name = "worktree")]
+pub struct Worktree {
+    #[argh(subcommand)]
+    /// what do we want to do with worktrees
+    pub command: WorktreeCommand,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// install the hooks, keeping any that are there and running them first
+#[argh(subcommand, name = "install")]
+pub struct HooksInstall {}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// remove the hooks, and put back the ones they were chained to
+#[argh(subcommand, name = "uninstall")]
+pub struct HooksUninstall {}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// run a hook, this is what the installed hooks call
+#[argh(subcommand, name = "run")]
+pub struct HooksRun {
+    #[argh(positional)]
+    /// pre-push, commit-msg or post-checkout
+    pub hook: std::string::String,
+    #[argh(positional)]
+    /// the arguments git gave the hook
+    pub args: std::vec::Vec<std::string::String>,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum HooksCommand {
+    Install(HooksInstall),
+    Uninstall(HooksUninstall),
+    Run(HooksRun),
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// Git hooks that check the changelog and commit messages as you go
+#[argh(subcommand, name = "hooks")]
+pub struct Hooks {
+    #[argh(subcommand)]
+    /// what do we want to do with hooks
+    pub command: HooksCommand,
 }
 
 //------------------------------------------------------------------------------
 #[derive(FromArgs, PartialEq, Debug)]
 /// rebase
 #[argh(subcommand, name = "rebase")]
-pub struct Rebase {}
+pub struct Rebase {
+    #[argh(switch, long = "continue")]
+    /// carry on with the rebase after resolving conflicts
+    pub continue_: bool,
+    #[argh(switch)]
+    /// give up on the rebase and restore the branch
+    pub abort: bool,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// merge the approved merge request and clean up the branch
+#[argh(subcommand, name = "finish")]
+pub struct Finish {}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// list the branches in flight, with their merge requests
+#[argh(subcommand, name = "list")]
+pub struct List {
+    #[argh(switch, short = 'a')]
+    /// list every branch type, not just this one
+    pub all: bool,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// switch to another branch, fetching it if it's only on the remote
+#[argh(subcommand, name = "switch")]
+pub struct Switch {
+    #[argh(positional)]
+    /// name of the feature/hotfix
+    pub name: std::string::String,
+}
 
 //------------------------------------------------------------------------------
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 pub enum Status {
     Start(Start),
+    Rename(Rename),
+    Abandon(Abandon),
+    Checkout(Checkout),
+    Backport(Backport),
     Rebase(Rebase),
+    Finish(Finish),
+    List(List),
+    Switch(Switch),
 }
 
 //------------------------------------------------------------------------------
@@ -89,6 +316,47 @@ pub struct Hotfix {
     pub status: Status,
 }
 
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// Working with any branch type declared in .git-me.yml
+#[argh(subcommand, name = "work")]
+pub struct Work {
+    #[argh(option, short = 't')]
+    /// the branch type, ie bugfix
+    pub type_: std::string::String,
+    #[argh(subcommand)]
+    /// the stage in the work
+    pub status: Status,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// start
+#[argh(subcommand, name = "start")]
+pub struct ReleaseStart {
+    #[argh(option, short = 'v')]
+    /// the version being released, this is also the tag
+    pub version: std::string::String,
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum ReleaseStatus {
+    Start(ReleaseStart),
+    Finish(Finish),
+}
+
+//------------------------------------------------------------------------------
+#[derive(FromArgs, PartialEq, Debug)]
+/// Working with release
+#[argh(subcommand, name = "release")]
+pub struct Release {
+    #[argh(subcommand)]
+    /// the stage in the release
+    pub status: ReleaseStatus,
+}
+
 //------------------------------------------------------------------------------
 #[derive(FromArgs, PartialEq, Debug)]
 /// Build a changelog for a version, by merging feature changelogs
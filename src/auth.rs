@@ -0,0 +1,119 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::config;
+
+const SSH_KEYS: [&str; 4] = ["id_ed25519", "id_ecdsa", "id_rsa", "id_dsa"];
+
+//------------------------------------------------------------------------------
+/// Works through the ways we know to authenticate with a remote. Libgit2 keeps
+/// asking for credentials until one works, so each call hands out the next
+/// one to try:
+///
+/// - ssh-agent
+/// - ssh keys in ~/.ssh, of any type
+/// - the git credential helper
+/// - the gitlab token from the git-me config, for https
+pub struct Credentials {
+    tried_agent: bool,
+    tried_keys: usize,
+    tried_helper: bool,
+    tried_token: bool,
+    tried: std::vec::Vec<&'static str>,
+}
+
+impl Credentials {
+    pub fn new() -> Self {
+        Self {
+            tried_agent: false,
+            tried_keys: 0,
+            tried_helper: false,
+            tried_token: false,
+            tried: std::vec::Vec::new(),
+        }
+    }
+
+    pub fn next(
+        &mut self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed_types: git2::CredentialType,
+    ) -> Result<git2::Cred, git2::Error> {
+        if allowed_types.contains(git2::CredentialType::USERNAME) {
+            return git2::Cred::username(username_from_url.unwrap_or("git"));
+        }
+
+        if allowed_types.contains(git2::CredentialType::SSH_KEY) {
+            let username = username_from_url.unwrap_or("git");
+
+            if !self.tried_agent {
+                self.tried_agent = true;
+                self.tried.push("ssh-agent");
+                if let Ok(cred) = git2::Cred::ssh_key_from_agent(username) {
+                    return Ok(cred);
+                }
+            }
+
+            while self.tried_keys < SSH_KEYS.len() {
+                let key = SSH_KEYS[self.tried_keys];
+                self.tried_keys += 1;
+
+                let path = ssh_dir().join(key);
+                if path.exists() {
+                    self.tried.push(key);
+                    return git2::Cred::ssh_key(username, None, &path, None);
+                }
+            }
+        }
+
+        if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            if !self.tried_helper {
+                self.tried_helper = true;
+                self.tried.push("git credential helper");
+                if let Ok(config) = git2::Config::open_default() {
+                    if let Ok(cred) = git2::Cred::credential_helper(
+                        &config,
+                        url,
+                        username_from_url,
+                    ) {
+                        return Ok(cred);
+                    }
+                }
+            }
+
+            if !self.tried_token {
+                self.tried_token = true;
+                if let Ok(config) = config::Config::open() {
+                    self.tried.push("gitlab token");
+                    return git2::Cred::userpass_plaintext(
+                        username_from_url.unwrap_or("oauth2"),
+                        &config.private_token,
+                    );
+                }
+            }
+        }
+
+        Err(git2::Error::new(
+            git2::ErrorCode::Auth,
+            git2::ErrorClass::Callback,
+            format!(
+                "Unable to authenticate with {}, tried: {}. Add your ssh key \
+                 to ssh-agent, or run 'git me setup' for https.",
+                url,
+                if self.tried.is_empty() {
+                    "nothing".to_string()
+                } else {
+                    self.tried.join(", ")
+                }
+            ),
+        ))
+    }
+}
+
+//------------------------------------------------------------------------------
+fn ssh_dir() -> std::path::PathBuf {
+    match directories::BaseDirs::new() {
+        Some(base_dirs) => base_dirs.home_dir().join(".ssh"),
+        None => std::path::PathBuf::from("~/.ssh"),
+    }
+}
@@ -1,412 +1,1250 @@
 //------------------------------------------------------------------------------
 // from+git_me@luketitley.com
 //------------------------------------------------------------------------------
+use crate::ancestry;
+use crate::auth;
 use crate::changelog;
+use crate::error;
+use crate::error::Context as _;
+use crate::history;
+use crate::lint;
+use crate::workflow;
+use crate::workspace;
 
-#[derive(Clone, Copy)]
-pub enum Type {
-    Feature,
-    Hotfix,
-}
+pub const NAMING: &str = r"^[a-zA-Z0-9_]+$";
 
 //------------------------------------------------------------------------------
-pub fn find_name() -> std::string::String {
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
-    let head = repo.head().expect("Unable to find head");
+/// A kind of branch in the workflow, these are declared in the repo's
+/// .git-me.yml, see workflow.rs.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Type {
+    #[serde(skip)]
+    pub name: std::string::String,
+    /// Put in front of the name to make the branch, ie 'feature/'
+    #[serde(default)]
+    pub prefix: std::string::String,
+    /// The branch we fork from, and keep up to date with
+    pub base: std::string::String,
+    /// The branch the merge request lands in, defaults to the base
+    #[serde(default)]
+    pub target: Option<std::string::String>,
+    /// The branch that needs the work merged back into it, once it has landed
+    /// in its target
+    #[serde(default)]
+    pub back_merge: Option<std::string::String>,
+    /// Regex the name has to match
+    #[serde(default = "default_naming")]
+    pub naming: std::string::String,
+    /// File in the repo to copy as the changelog stub
+    #[serde(default)]
+    pub changelog: Option<std::string::String>,
+    /// Releases aggregate the changelogs when they start, and are tagged when
+    /// they finish
+    #[serde(default)]
+    pub release: bool,
+}
 
-    head.shorthand().expect("Head has no name").to_string()
+fn default_naming() -> std::string::String {
+    NAMING.to_string()
 }
 
 //------------------------------------------------------------------------------
-pub fn find_last_commit_msg() -> std::string::String {
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
-    let head = repo.head().expect("Unable to find head");
-    let commit = head.peel_to_commit().expect("Unable to find latest commit");
+fn find_local<'r>(
+    repo: &'r git2::Repository,
+    branch_name: &str,
+) -> error::Result<git2::Branch<'r>> {
+    repo.find_branch(branch_name, git2::BranchType::Local)
+        .git_error(&format!("Unable to find branch {}", branch_name))
+}
 
-    commit.message().expect("Commit message is not utf8").to_string()
+//------------------------------------------------------------------------------
+fn find_tracking<'r>(
+    workspace: &workspace::Workspace,
+    repo: &'r git2::Repository,
+    branch_name: &str,
+) -> error::Result<git2::Branch<'r>> {
+    let remote_branch = remote_branch(workspace, branch_name)?;
+    repo.find_branch(&remote_branch, git2::BranchType::Remote)
+        .git_error(&format!("Unable to find {}", remote_branch))
 }
 
 //------------------------------------------------------------------------------
-pub fn find_path() -> std::path::PathBuf {
-    git2::Repository::discover("./")
-        .expect("Unable to find git repo")
-        .path()
-        .parent()
-        .expect("Unable to find the repo folder")
-        .to_path_buf()
+fn target_oid(branch: &git2::Branch) -> error::Result<git2::Oid> {
+    branch
+        .get()
+        .target()
+        .git_error("Unable to find reference target")
 }
 
 //------------------------------------------------------------------------------
-pub fn find_type() -> Option<Type> {
-    let branch_name = find_name();
-    let parts: Vec<_> = branch_name.split("/").collect();
+pub fn find_name(
+    workspace: &workspace::Workspace,
+) -> error::Result<std::string::String> {
+    let repo = workspace.open()?;
+    let head = repo.head().git_error("Unable to find head")?;
 
-    if (parts.len() > 1) {
-        if parts[0] == "feature" {
-            return Some(Type::Feature);
-        }
+    Ok(head.shorthand().git_error("Head has no name")?.to_string())
+}
 
-        if parts[0] == "hotfix" {
-            return Some(Type::Hotfix);
-        }
-    }
+//------------------------------------------------------------------------------
+pub fn find_last_commit_msg(
+    workspace: &workspace::Workspace,
+) -> error::Result<std::string::String> {
+    let repo = workspace.open()?;
+    let head = repo.head().git_error("Unable to find head")?;
+    let commit = head
+        .peel_to_commit()
+        .git_error("Unable to find latest commit")?;
+
+    Ok(commit
+        .message()
+        .git_error("Commit message is not utf8")?
+        .to_string())
+}
 
-    None
+//------------------------------------------------------------------------------
+pub fn find_type(
+    workspace: &workspace::Workspace,
+) -> error::Result<Option<Type>> {
+    Ok(workflow::Workflow::open(workspace)?
+        .find_by_branch(&find_name(workspace)?))
 }
 
 //------------------------------------------------------------------------------
-pub fn resolve(type_: Type, name: &str) -> std::string::String {
-    match type_ {
-        Type::Feature => format!("feature/{}", name),
-        Type::Hotfix => format!("hotfix/{}", name),
-    }
+pub fn resolve(type_: &Type, name: &str) -> std::string::String {
+    format!("{}{}", type_.prefix, name)
 }
 
 //------------------------------------------------------------------------------
-pub fn base(type_: Type) -> &'static str {
-    match type_ {
-        Type::Feature => "develop",
-        Type::Hotfix => "develop", // Need to switch this to master
-    }
+pub fn base(type_: &Type) -> &str {
+    &type_.base
 }
 
 //------------------------------------------------------------------------------
-// PushDir
+pub fn target(type_: &Type) -> &str {
+    type_.target.as_deref().unwrap_or(&type_.base)
+}
+
 //------------------------------------------------------------------------------
-struct PushDir {
-    path: std::path::PathBuf,
+pub fn back_merge(type_: &Type) -> Option<&str> {
+    type_.back_merge.as_deref()
 }
 
-impl PushDir {
-    pub fn new(path: &std::path::Path) -> std::io::Result<PushDir> {
-        let current_dir = std::env::current_dir()?;
-        std::env::set_current_dir(path)?;
-        Ok(Self { path: current_dir })
-    }
+//------------------------------------------------------------------------------
+/// The branch a stacked branch was started on, see 'start --on'. This lives in
+/// the git config so it goes away with the branch.
+pub fn stacked_on(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<Option<std::string::String>> {
+    let repo = workspace.open()?;
+    let stacked_on = repo
+        .config()
+        .git_error("Unable to read git config")?
+        .get_string(&format!("branch.{}.git-me-on", branch_name))
+        .ok();
+    Ok(stacked_on)
 }
 
-impl std::ops::Drop for PushDir {
-    fn drop(&mut self) {
-        std::env::set_current_dir(&self.path)
-            .expect("Unable to set the current dir");
+//------------------------------------------------------------------------------
+pub fn stack_on(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+    parent: Option<&str>,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let mut config = repo.config().git_error("Unable to read git config")?;
+    let key = format!("branch.{}.git-me-on", branch_name);
+    match parent {
+        Some(parent) => config
+            .set_str(&key, parent)
+            .git_error("Unable to record stacked branch"),
+        // It's fine if it wasn't stacked to begin with
+        None => {
+            config.remove(&key).unwrap_or(());
+            Ok(())
+        }
     }
 }
 
 //------------------------------------------------------------------------------
-pub fn branch(type_: Type, name: &str) {
-    let branch_name = resolve(type_, name);
-    let base = base(type_);
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
-
-    let repo_path = repo
-        .path()
-        .parent()
-        .expect("Unable to find the repo folder")
-        .to_path_buf();
+/// Local branches stacked directly on top of parent.
+pub fn stacked_children(
+    workspace: &workspace::Workspace,
+    parent: &str,
+) -> error::Result<std::vec::Vec<std::string::String>> {
+    let repo = workspace.open()?;
+    let config = repo.config().git_error("Unable to read git config")?;
+
+    let mut children = std::vec::Vec::new();
+    let entries = config
+        .entries(Some(r"^branch\..*\.git-me-on$"))
+        .git_error("Unable to read git config")?;
+    for entry in &entries {
+        let entry = entry.git_error("Unable to read git config entry")?;
+        if entry.value() == Some(parent) {
+            let name = entry.name().git_error("Config name is not utf8")?;
+            children.push(
+                name["branch.".len()..name.len() - ".git-me-on".len()]
+                    .to_string(),
+            );
+        }
+    }
+    Ok(children)
+}
 
-    let current_dir = PushDir::new(&repo_path);
+//------------------------------------------------------------------------------
+/// Where this particular branch forks from, the branch it's stacked on, or
+/// the base for its type.
+pub fn base_of(
+    workspace: &workspace::Workspace,
+    type_: &Type,
+    branch_name: &str,
+) -> error::Result<std::string::String> {
+    Ok(stacked_on(workspace, branch_name)?
+        .unwrap_or_else(|| base(type_).to_string()))
+}
 
-    let base_branch = repo
-        .find_branch(base, git2::BranchType::Local)
-        .expect(&format!("Unable to find branch {}", base));
-    let base_reference = base_branch.get();
-    let base_oid = base_reference
-        .target()
-        .expect("Unable to find reference target");
-    let commit = repo
-        .find_commit(base_oid)
-        .expect("Unable to find head commit");
+//------------------------------------------------------------------------------
+/// Where this particular branch's merge request lands.
+pub fn target_of(
+    workspace: &workspace::Workspace,
+    type_: &Type,
+    branch_name: &str,
+) -> error::Result<std::string::String> {
+    Ok(stacked_on(workspace, branch_name)?
+        .unwrap_or_else(|| target(type_).to_string()))
+}
 
-    repo.set_head(
-        base_reference
-            .name()
-            .expect("Unable to get base branch reference name"),
-    )
-    .expect(&format!(
-        "Unable to set HEAD to point to {} before branching",
-        base
-    ));
+//------------------------------------------------------------------------------
+/// The name of the changelog that goes with a branch. Releases carry the
+/// aggregate changelog for their version.
+pub fn changelog_name(type_: &Type, branch_name: &str) -> std::string::String {
+    if type_.release {
+        changelog::release_name(branch_name.trim_start_matches(&type_.prefix))
+    } else {
+        branch_name.to_string()
+    }
+}
 
-    let mut branch = repo
-        .branch(&branch_name, &commit, false)
-        .expect("Unable to create branch");
+//------------------------------------------------------------------------------
+pub fn branch(
+    workspace: &workspace::Workspace,
+    type_: &Type,
+    name: &str,
+    on: Option<&str>,
+    worktree: Option<&std::path::Path>,
+    changelog_msg: Option<&str>,
+) -> error::Result<()> {
+    let branch_name = resolve(type_, name);
+    let base = on.unwrap_or_else(|| base(type_));
+    let main_repo = workspace.open()?;
 
-    repo.set_head(
-        branch
-            .get()
-            .name()
-            .expect("Unable to get the refname for new branch"),
-    )
-    .expect("Unable to set HEAD to point to new branch");
+    // Hotfixes fork from master, which many people never check out locally
+    if main_repo
+        .find_branch(base, git2::BranchType::Local)
+        .is_err()
+    {
+        track(workspace, base)?;
+    }
 
-    let changelog = changelog::create_stub(&branch_name, None);
-    let mut index = repo.index().expect("Unable to create index for changelog");
+    let base_branch = find_local(&main_repo, base)?;
+    let base_oid = target_oid(&base_branch)?;
+    let commit = main_repo
+        .find_commit(base_oid)
+        .git_error("Unable to find head commit")?;
+
+    let branch_workspace = match worktree {
+        // Leave the main checkout alone, and do the work in a new worktree
+        Some(worktree) => {
+            let branch = main_repo
+                .branch(&branch_name, &commit, false)
+                .git_error("Unable to create branch")?;
+
+            let mut options = git2::WorktreeAddOptions::new();
+            options.reference(Some(branch.get()));
+            let worktree = main_repo
+                .worktree(&worktree_name(worktree)?, worktree, Some(&options))
+                .git_error(&format!(
+                    "Unable to create worktree {}",
+                    worktree.display()
+                ))?;
+
+            workspace::Workspace::discover(worktree.path())?
+        }
+        None => {
+            // Check out base first, so the changelog lands on its files and
+            // not the ones of whatever branch we started on
+            switch(workspace, base)?;
+
+            let branch = main_repo
+                .branch(&branch_name, &commit, false)
+                .git_error("Unable to create branch")?;
+
+            main_repo
+                .set_head(
+                    branch.get().name().git_error(
+                        "Unable to get the refname for new branch",
+                    )?,
+                )
+                .git_error("Unable to set HEAD to point to new branch")?;
+
+            workspace.clone()
+        }
+    };
+    let repo = branch_workspace.open()?;
+
+    let changelog = if type_.release {
+        let prefixes =
+            workflow::Workflow::open(&branch_workspace)?.changelog_prefixes();
+        changelog::aggregate(&branch_workspace, name, &prefixes)?
+    } else if let Some(template) = &type_.changelog {
+        changelog::create_from_template(
+            &branch_workspace,
+            &branch_name,
+            std::path::Path::new(template),
+        )?
+    } else if let Some(msg) = changelog_msg {
+        changelog::create_with_msg(&branch_workspace, &branch_name, msg)?
+    } else {
+        changelog::create_stub(&branch_workspace, &branch_name)?
+    };
+    let changelog = changelog
+        .strip_prefix(branch_workspace.path())
+        .git_error("Changelog isn't in the repo")?;
+    let mut index = repo
+        .index()
+        .git_error("Unable to create index for changelog")?;
     index
-        .add_path(changelog.as_path())
-        .expect("Unable to add changelog to index");
-
-    let index_oid = index.write_tree().expect("Unable to write index");
+        .add_path(changelog)
+        .git_error("Unable to add changelog to index")?;
+    index.write().git_error("Unable to write index")?;
 
+    // The fork is base's files and the changelog, whatever else the index has
+    let commit = repo
+        .find_commit(base_oid)
+        .git_error("Unable to find head commit")?;
+    let blob = repo
+        .blob_path(&branch_workspace.path().join(changelog))
+        .git_error("Unable to add changelog to the repo")?;
+    let tree = git2::build::TreeUpdateBuilder::new()
+        .upsert(changelog, blob, git2::FileMode::Blob)
+        .create_updated(
+            &repo,
+            &commit.tree().git_error("Unable to find base tree")?,
+        )
+        .git_error("Unable to add changelog to the tree")?;
     let tree = repo
-        .find_tree(index_oid)
-        .expect("Unable to find tree for new index");
+        .find_tree(tree)
+        .git_error("Unable to find tree for new index")?;
 
+    // Name the branch rather than HEAD, libgit2 can't follow HEAD in a linked
+    // worktree
+    let signature = repo.signature().git_error("Unable to obtain signature")?;
     repo.commit(
-        Some("HEAD"),
-        &repo.signature().expect("Unable to obtain signature"),
-        &repo.signature().expect("Unable to obtain signature"),
+        Some(&format!("refs/heads/{}", branch_name)),
+        &signature,
+        &signature,
         &format!("Fork branch '{}' from '{}'", branch_name, base),
         &tree,
         &[&commit],
     )
-    .expect("Unable to make initial commit");
+    .git_error("Unable to make initial commit")?;
 
-    repo.checkout_head(None).expect("Reset everything to head");
+    repo.checkout_head(None)
+        .git_error("Reset everything to head")
 }
 
-fn find_commit(reference: &git2::Commit, commit: &git2::Commit) -> bool {
-    if commit.id() == reference.id() {
-        return true;
-    }
-    for p in commit.parents() {
-        if find_commit(reference, &p) {
-            return true;
+//------------------------------------------------------------------------------
+/// Undo branch(workspace), going back to the branch we started on.
+pub fn unbranch(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+    previous: &str,
+    worktree: Option<&std::path::Path>,
+) -> error::Result<()> {
+    match worktree {
+        Some(worktree) => {
+            remove_worktree(workspace, &worktree_name(worktree)?, true)?
         }
+        None => switch(workspace, previous)?,
     }
-    return false;
+    delete(workspace, branch_name)
 }
 
 //------------------------------------------------------------------------------
-pub fn set_upstream(branch_name: &str) {
-    let repo =
-        git2::Repository::discover("./").expect("unable to find git repo");
+// Worktrees
+//------------------------------------------------------------------------------
+fn worktree_name(path: &std::path::Path) -> error::Result<std::string::String> {
+    Ok(path
+        .file_name()
+        .git_error("Worktree path has no name")?
+        .to_string_lossy()
+        .to_string())
+}
 
-    let mut branch = repo
-        .find_branch(branch_name, git2::BranchType::Local)
-        .expect(&format!("unable to find branch {}", branch_name));
+//------------------------------------------------------------------------------
+/// Where 'start --worktree' puts the worktree for a branch, next to the main
+/// checkout.
+pub fn worktree_path(
+    workspace: &workspace::Workspace,
+    name: &str,
+) -> error::Result<std::path::PathBuf> {
+    let repo_path = find_main_path(workspace)?;
+    let parent = repo_path
+        .parent()
+        .git_error("Unable to find the folder the repo is in")?;
+    Ok(parent.join(format!("{}-{}", worktree_name(&repo_path)?, name)))
+}
 
-    branch
-        .set_upstream(Some(&format!("origin/{}", branch_name)))
-        .expect("Unable to set the upstream branch");
-}
-
-//------------------------------------------------------------------------------
-pub fn rebase_place_holder(type_: Type, fullname: &str) {
-    /*
-    git checkout {0} && git pull --rebase && git checkout {1} && git rebase {0} && git push -f origin {1}
-    */
-    let base = base(type_);
-
-    // git checkout base
-    std::process::Command::new("git")
-        .arg("checkout")
-        .arg(base)
-        .spawn()
-        .expect("failed to execute git checkout")
-        .wait()
-        .expect("failed to wait on git checkout");
-
-    // git pull --rebase
-    std::process::Command::new("git")
-        .arg("pull")
-        .arg("--rebase")
-        .spawn()
-        .expect("failed to execute git pull")
-        .wait()
-        .expect("failed to wait on git pull");
-
-    // git checkout branch
-    std::process::Command::new("git")
-        .arg("checkout")
-        .arg(fullname)
-        .spawn()
-        .expect(&format!("failed to execute git checkout to {}", fullname))
-        .wait()
-        .expect(&format!("failed to wait on git checkout to {}", fullname));
-
-    // git rebase develop
-    std::process::Command::new("git")
-        .arg("rebase")
-        .arg(base)
-        .spawn()
-        .expect(&format!(
-            "failed to execute git rebase {} on develop",
-            fullname
+//------------------------------------------------------------------------------
+/// The main checkout, even when we're running in a linked worktree.
+pub fn find_main_path(
+    workspace: &workspace::Workspace,
+) -> error::Result<std::path::PathBuf> {
+    let repo = workspace.open()?;
+
+    Ok(common_dir(&repo)?
+        .parent()
+        .git_error("Unable to find the repo folder")?
+        .to_path_buf())
+}
+
+//------------------------------------------------------------------------------
+/// The main .git folder, even when we're running in a linked worktree.
+fn common_dir(repo: &git2::Repository) -> error::Result<std::path::PathBuf> {
+    // Linked worktrees keep a pointer back to the main .git folder
+    if repo.is_worktree() {
+        let common_dir = std::fs::read_to_string(repo.path().join("commondir"))
+            .git_error("Unable to read the worktree's commondir")?;
+        repo.path()
+            .join(common_dir.trim())
+            .canonicalize()
+            .git_error("Unable to find the main repo")
+    } else {
+        Ok(repo.path().to_path_buf())
+    }
+}
+
+//------------------------------------------------------------------------------
+/// Where git looks for hooks, core.hooksPath if it's set. Linked worktrees
+/// share the main repo's.
+pub fn hooks_path(
+    workspace: &workspace::Workspace,
+) -> error::Result<std::path::PathBuf> {
+    let repo = workspace.open()?;
+    let config = repo.config().git_error("Unable to read the git config")?;
+
+    // A relative hooksPath is from the top of the checkout
+    match config.get_path("core.hooksPath") {
+        Ok(path) => Ok(workspace.path().join(path)),
+        Err(_) => Ok(common_dir(&repo)?.join("hooks")),
+    }
+}
+
+//------------------------------------------------------------------------------
+pub struct Worktree {
+    pub name: std::string::String,
+    pub path: std::path::PathBuf,
+    pub branch: Option<std::string::String>,
+}
+
+//------------------------------------------------------------------------------
+pub fn worktrees(
+    workspace: &workspace::Workspace,
+) -> error::Result<std::vec::Vec<Worktree>> {
+    let repo = workspace.open()?;
+
+    let mut result = std::vec::Vec::new();
+    for name in repo
+        .worktrees()
+        .git_error("Unable to list worktrees")?
+        .iter()
+    {
+        let name = name.git_error("Worktree name is not utf8")?;
+        let worktree = repo
+            .find_worktree(name)
+            .git_error("Unable to open worktree")?;
+
+        let branch = git2::Repository::open_from_worktree(&worktree)
+            .ok()
+            .and_then(|r| {
+                r.head()
+                    .ok()
+                    .and_then(|h| h.shorthand().map(str::to_string))
+            });
+
+        result.push(Worktree {
+            name: name.to_string(),
+            path: worktree.path().to_path_buf(),
+            branch,
+        });
+    }
+    Ok(result)
+}
+
+//------------------------------------------------------------------------------
+/// Remove a worktree and its files. The branch is left alone.
+pub fn remove_worktree(
+    workspace: &workspace::Workspace,
+    name: &str,
+    force: bool,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let worktree = repo
+        .find_worktree(name)
+        .git_error(&format!("Unable to find worktree {}", name))?;
+
+    if !force {
+        let worktree_repo = git2::Repository::open_from_worktree(&worktree);
+        if let Ok(worktree_repo) = worktree_repo {
+            let statuses = worktree_repo
+                .statuses(None)
+                .git_error("Error getting status")?;
+            if statuses.iter().any(|s| !s.status().is_ignored()) {
+                return Err(error::Error::user(&format!(
+                    "Worktree {} has uncommited changes",
+                    name
+                ))
+                .hint("Use --force to remove it anyway"));
+            }
+        }
+    }
+
+    worktree
+        .prune(Some(
+            git2::WorktreePruneOptions::new()
+                .valid(true)
+                .locked(force)
+                .working_tree(true),
         ))
-        .wait()
-        .expect(&format!(
-            "failed to wait on git checkout {} on develop",
-            fullname
-        ));
+        .git_error(&format!("Unable to remove worktree {}", name))
+}
+
+//------------------------------------------------------------------------------
+pub fn set_upstream(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let mut branch = find_local(&repo, branch_name)?;
 
-    // git push -f origin {0}
-    std::process::Command::new("git")
-        .arg("push")
-        .arg("-f")
-        .arg("origin")
-        .arg(fullname)
-        .spawn()
-        .expect(&format!("failed to execute git push for {}", fullname))
-        .wait()
-        .expect(&format!("failed to wait on git push for {}", fullname));
+    branch
+        .set_upstream(Some(&remote_branch(workspace, branch_name)?))
+        .git_error("Unable to set the upstream branch")
 }
 
 //------------------------------------------------------------------------------
-pub fn well_formed(name: &str) -> bool {
-    regex::Regex::new(r"^[a-zA-Z0-9_]+$")
-        .expect("Unable to compile regex")
-        .is_match(name)
+// Rebase
+//------------------------------------------------------------------------------
+pub enum RebaseOutcome {
+    Finished,
+    Conflicts(std::vec::Vec<std::string::String>),
 }
 
 //------------------------------------------------------------------------------
-pub fn rebase_not_finished(type_: Type, _fullname: &str) {
-    let base = base(type_);
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
-    let mut base_branch = repo
-        .find_branch(base, git2::BranchType::Local)
-        .expect(&format!("Unable to find branch {}", base));
+fn remote_callbacks<'a>() -> git2::RemoteCallbacks<'a> {
+    let mut credentials = auth::Credentials::new();
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        credentials.next(url, username_from_url, allowed_types)
+    });
+    callbacks
+}
 
-    let base_reference = base_branch.get_mut();
+//------------------------------------------------------------------------------
+/// The remote we work with. This is 'origin' unless the clone says otherwise
+/// with 'git config git-me.remote <name>'.
+pub fn remote_name(
+    workspace: &workspace::Workspace,
+) -> error::Result<std::string::String> {
+    let repo = workspace.open()?;
+    Ok(repo
+        .config()
+        .git_error("Unable to read git config")?
+        .get_string("git-me.remote")
+        .unwrap_or_else(|_| "origin".to_string()))
+}
 
-    println!("    * Switch to {}", base);
-    let base_reference_name = base_reference
-        .name()
-        .expect("Unable to get base branch reference name");
+//------------------------------------------------------------------------------
+pub fn remote_branch(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<std::string::String> {
+    Ok(format!("{}/{}", remote_name(workspace)?, branch_name))
+}
 
-    println!("    * Fetch");
+//------------------------------------------------------------------------------
+pub fn fetch(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let remote_name = remote_name(workspace)?;
     let mut remote = repo
-        .find_remote("origin")
-        .expect("Unable to find remote repo");
+        .find_remote(&remote_name)
+        .git_error("Unable to find remote repo")?;
 
-    let mut callbacks = git2::RemoteCallbacks::new();
-    callbacks.credentials(|_url, username_from_url, _allowed_types| {
-        git2::Cred::ssh_key(
-            username_from_url.unwrap(),
-            None,
-            std::path::Path::new(&format!(
-                "{}/.ssh/id_rsa",
-                std::env::var("HOME").unwrap()
-            )),
+    let ref_spec = format!(
+        "+refs/heads/{0}:refs/remotes/{1}/{0}",
+        branch_name, remote_name
+    );
+    remote
+        .fetch(
+            &[&ref_spec],
+            Some(
+                git2::FetchOptions::new().remote_callbacks(remote_callbacks()),
+            ),
             None,
         )
-    });
+        .git_error(&format!("Failed to fetch {}", branch_name))
+}
 
+//------------------------------------------------------------------------------
+/// Fetch everything the remote is configured to, dropping the tracking
+/// branches that have gone from it.
+pub fn fetch_all(workspace: &workspace::Workspace) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let remote_name = remote_name(workspace)?;
+    let mut remote = repo
+        .find_remote(&remote_name)
+        .git_error("Unable to find remote repo")?;
+
+    let no_ref_specs: &[&str] = &[];
     remote
         .fetch(
-            &[&base],
-            Some(git2::FetchOptions::new().remote_callbacks(callbacks)),
+            no_ref_specs,
+            Some(
+                git2::FetchOptions::new()
+                    .remote_callbacks(remote_callbacks())
+                    .prune(git2::FetchPrune::On),
+            ),
             None,
         )
-        .expect("Failed to fetch");
-
-    let remote_reference = repo
-        .find_reference(
-            repo.branch_upstream_name(base_reference_name)
-                .expect("Unable to find upstream branch")
-                .as_str()
-                .expect("refname is not utf8"),
-        )
-        .expect("Unable to obtain remote reference");
-    let remote_commit = repo
-        .find_commit(
-            remote_reference
-                .target()
-                .expect("Unable to find reference target"),
-        )
-        .expect("Unable to find head commit");
+        .git_error(&format!("Failed to fetch {}", remote_name))
+}
+
+//------------------------------------------------------------------------------
+fn conflicts(
+    repo: &git2::Repository,
+) -> error::Result<std::vec::Vec<std::string::String>> {
+    let index = repo.index().git_error("Unable to open the index")?;
+    history::conflicts(&index)
+}
+
+//------------------------------------------------------------------------------
+fn rebase_commit(
+    repo: &git2::Repository,
+    rebase: &mut git2::Rebase,
+) -> error::Result<()> {
+    let signature = repo.signature().git_error("Unable to obtain signature")?;
+    match rebase.commit(None, &signature, None) {
+        Ok(_) => Ok(()),
+        // The patch is already in the base, so there's nothing to commit
+        Err(e) if e.code() == git2::ErrorCode::Applied => Ok(()),
+        Err(e) => Err(e).git_error("Unable to commit rebased change"),
+    }
+}
+
+//------------------------------------------------------------------------------
+fn rebase_replay(
+    repo: &git2::Repository,
+    rebase: &mut git2::Rebase,
+) -> error::Result<RebaseOutcome> {
+    while let Some(operation) = rebase.next() {
+        let operation =
+            operation.git_error("Unable to apply rebase operation")?;
+        println!("        * Apply {}", operation.id());
+
+        let conflicts = conflicts(repo)?;
+        if !conflicts.is_empty() {
+            return Ok(RebaseOutcome::Conflicts(conflicts));
+        }
+
+        rebase_commit(repo, rebase)?;
+    }
+
+    rebase.finish(None).git_error("Unable to finish rebase")?;
+    Ok(RebaseOutcome::Finished)
+}
+
+//------------------------------------------------------------------------------
+pub fn rebase(
+    workspace: &workspace::Workspace,
+    base: &str,
+    branch_name: &str,
+) -> error::Result<RebaseOutcome> {
+    rebase_onto(workspace, base, None, branch_name)
+}
+
+//------------------------------------------------------------------------------
+/// Move a branch that was stacked on top of another, onto base. Only the
+/// commits after old_parent are replayed.
+pub fn restack(
+    workspace: &workspace::Workspace,
+    base: &str,
+    old_parent: git2::Oid,
+    branch_name: &str,
+) -> error::Result<RebaseOutcome> {
+    rebase_onto(workspace, base, Some(old_parent), branch_name)
+}
 
-    let base_commit = repo
-        .find_commit(
-            base_reference
-                .target()
-                .expect("Unable to find reference target"),
+//------------------------------------------------------------------------------
+fn rebase_onto(
+    workspace: &workspace::Workspace,
+    base: &str,
+    old_parent: Option<git2::Oid>,
+    branch_name: &str,
+) -> error::Result<RebaseOutcome> {
+    // Bring the base up to date with the remote
+    println!("        * Fetch {}", base);
+    fetch(workspace, base)?;
+
+    let repo = workspace.open()?;
+
+    if repo.state() != git2::RepositoryState::Clean {
+        return Err(error::Error::user(
+            "A rebase or merge is already in progress",
         )
-        .expect("Unable to find head commit");
+        .hint("Try 'rebase --continue' or 'rebase --abort'"));
+    }
 
-    print!("    * Verify history");
+    let branch = find_local(&repo, branch_name)?;
+    let branch = repo
+        .reference_to_annotated_commit(branch.get())
+        .git_error("Unable to annotate branch commit")?;
+
+    let onto = find_tracking(workspace, &repo, base)?;
+    let onto = repo
+        .reference_to_annotated_commit(onto.get())
+        .git_error("Unable to annotate base commit")?;
+
+    println!(
+        "        * Replay {} onto {}",
+        branch_name,
+        remote_branch(workspace, base)?
+    );
+    let mut rebase = match old_parent {
+        Some(old_parent) => {
+            let upstream = repo
+                .find_annotated_commit(old_parent)
+                .git_error("Unable to annotate old parent commit")?;
+            repo.rebase(Some(&branch), Some(&upstream), Some(&onto), None)
+        }
+        None => repo.rebase(Some(&branch), Some(&onto), None, None),
+    }
+    .git_error("Unable to start rebase")?;
 
-    // Ensure we can find the head commit of our local branch in the remote one
-    if find_commit(&base_commit, &remote_commit) {
-        println!(" ok");
-    } else {
-        println!(" failed");
-        panic!("You have local changes on {}. You've gone rogue. Get those changes pushed through a merge request.", base);
+    rebase_replay(&repo, &mut rebase)
+}
+
+//------------------------------------------------------------------------------
+pub fn rebase_continue(
+    workspace: &workspace::Workspace,
+) -> error::Result<RebaseOutcome> {
+    let repo = workspace.open()?;
+    let mut rebase = repo
+        .open_rebase(None)
+        .map_err(|_| error::Error::user("There is no rebase in progress"))?;
+
+    let conflicts = conflicts(&repo)?;
+    if !conflicts.is_empty() {
+        return Ok(RebaseOutcome::Conflicts(conflicts));
     }
 
-    let statuses = repo.statuses(None).expect("Error getting status");
-    if !statuses.is_empty() {
-        println!("You have un commited changes on {}.", base);
-        for s in statuses.iter() {
-            print!("    status '{:?}'", s.status());
-            if let Some(path) = s.path() {
-                print!(" path '{:?}'", path);
-            }
-            println!();
+    // Commit the operation the user has just resolved
+    if rebase.operation_current().is_some() {
+        rebase_commit(&repo, &mut rebase)?;
+    }
+
+    rebase_replay(&repo, &mut rebase)
+}
+
+//------------------------------------------------------------------------------
+pub fn rebase_abort(workspace: &workspace::Workspace) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let mut rebase = repo
+        .open_rebase(None)
+        .map_err(|_| error::Error::user("There is no rebase in progress"))?;
+    rebase.abort().git_error("Unable to abort rebase")
+}
+
+//------------------------------------------------------------------------------
+/// A well formed name made from free text, ie an issue title, with the words
+/// lowercased and joined by underscores. Empty if there are no words.
+pub fn name_from(text: &str) -> std::string::String {
+    const MAX_LEN: usize = 40;
+
+    let mut name = std::string::String::new();
+    for word in text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        if !name.is_empty() && name.len() + word.len() >= MAX_LEN {
+            break;
+        }
+        if !name.is_empty() {
+            name.push('_');
         }
-        panic!("Exiting.");
+        name.push_str(&word.to_ascii_lowercase());
     }
+    name.truncate(MAX_LEN);
+    name
+}
+
+//------------------------------------------------------------------------------
+pub fn well_formed_for(type_: &Type, name: &str) -> error::Result<bool> {
+    Ok(regex::Regex::new(&type_.naming)
+        .config_error(&format!("Invalid naming rule for {}", type_.name))?
+        .is_match(name))
+}
 
-    println!("    * Switching to latest {}", base);
-    base_reference
-        .set_target(remote_commit.id(), "bump base to latest")
-        .expect("error jumping base up to state of remote");
+//------------------------------------------------------------------------------
+pub fn tag(workspace: &workspace::Workspace, name: &str) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let head = repo
+        .head()
+        .git_error("Unable to find head")?
+        .peel(git2::ObjectType::Commit)
+        .git_error("Unable to find head commit")?;
+
+    repo.tag(
+        name,
+        &head,
+        &repo.signature().git_error("Unable to obtain signature")?,
+        &format!("Release {}", name),
+        false,
+    )
+    .git_error(&format!("Unable to create tag {}", name))?;
 
-    repo.checkout_head(None).expect("Reset everything to head");
+    push_ref_spec(workspace, &format!("refs/tags/{0}:refs/tags/{0}", name))
 }
 
 //------------------------------------------------------------------------------
-pub fn push(branch_name: &str) {
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
+fn push_ref_spec(
+    workspace: &workspace::Workspace,
+    ref_spec: &str,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
     let mut remote = repo
-        .find_remote("origin")
-        .expect("Unable to find remote repo");
+        .find_remote(&remote_name(workspace)?)
+        .git_error("Unable to find remote repo")?;
+
+    let mut rejected = None;
+    {
+        let mut callbacks = remote_callbacks();
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                rejected = Some(format!("{} ({})", refname, status));
+            }
+            Ok(())
+        });
+
+        remote
+            .push(
+                &[ref_spec],
+                Some(git2::PushOptions::new().remote_callbacks(callbacks)),
+            )
+            .git_error("Failed to push")?;
+    }
 
-    let mut callbacks = git2::RemoteCallbacks::new();
-    callbacks.credentials(|_url, username_from_url, _allowed_types| {
-        git2::Cred::ssh_key(
-            username_from_url.expect("Unable to authenticate ssh protocal for push. Make sure you have $HOME/.ssh/id_rsa file, and that you have permissions to the git repo"),
-            None,
-            std::path::Path::new(&format!(
-                "{}/.ssh/id_rsa",
-                std::env::var("HOME").unwrap()
-            )),
-            None,
-        )
-    });
+    match rejected {
+        Some(rejected) => Err(error::Error::git(&format!(
+            "Remote rejected push of {}",
+            rejected
+        ))),
+        None => Ok(()),
+    }
+}
 
-    let ref_spec = format!("refs/heads/{0}:refs/heads/{0}", branch_name);
-    remote
-        .push(
-            &[&ref_spec],
-            Some(git2::PushOptions::new().remote_callbacks(callbacks)),
-        )
-        .expect("Failed to push");
+//------------------------------------------------------------------------------
+pub fn push(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    push_ref_spec(
+        workspace,
+        &format!("refs/heads/{0}:refs/heads/{0}", branch_name),
+    )
+}
+
+//------------------------------------------------------------------------------
+pub fn force_push(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    push_ref_spec(
+        workspace,
+        &format!("+refs/heads/{0}:refs/heads/{0}", branch_name),
+    )
+}
+
+//------------------------------------------------------------------------------
+/// Force push, but only if the remote branch is still where we last fetched
+/// it, so nobody else's work gets thrown away.
+pub fn force_push_with_lease(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let expected = match find_tracking(workspace, &repo, branch_name) {
+        Ok(tracking) => Some(target_oid(&tracking)?),
+        Err(_) => None,
+    };
+
+    if remote_target(workspace, branch_name)? != expected {
+        return Err(error::Error::user(&format!(
+            "{} has changed since you last fetched it",
+            remote_branch(workspace, branch_name)?
+        ))
+        .hint("Someone else has pushed to it, fetch and take a look first"));
+    }
+    force_push(workspace, branch_name)
+}
+
+//------------------------------------------------------------------------------
+pub fn delete_remote(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    push_ref_spec(workspace, &format!(":refs/heads/{}", branch_name))
+}
+
+//------------------------------------------------------------------------------
+pub fn remote_branch_exists(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<bool> {
+    Ok(remote_target(workspace, branch_name)?.is_some())
+}
+
+//------------------------------------------------------------------------------
+/// Where the branch is on the remote right now, rather than when we last
+/// fetched.
+fn remote_target(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<Option<git2::Oid>> {
+    let repo = workspace.open()?;
+    let mut remote = repo
+        .find_remote(&remote_name(workspace)?)
+        .git_error("Unable to find remote repo")?;
+
+    let connection = remote
+        .connect_auth(git2::Direction::Fetch, Some(remote_callbacks()), None)
+        .git_error("Unable to connect to remote")?;
+
+    let refname = format!("refs/heads/{}", branch_name);
+    let target = connection
+        .list()
+        .git_error("Unable to list remote branches")?
+        .iter()
+        .find(|head| head.name() == refname)
+        .map(|head| head.oid());
+    Ok(target)
+}
+
+//------------------------------------------------------------------------------
+pub fn delete(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+
+    find_local(&repo, branch_name)?
+        .delete()
+        .git_error(&format!("Unable to delete {}", branch_name))?;
+
+    // Drop the remote tracking branch too, if we have one
+    if let Ok(mut tracking) = find_tracking(workspace, &repo, branch_name) {
+        tracking
+            .delete()
+            .git_error("Unable to delete remote tracking branch")?;
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Create a local branch from its remote counterpart and track it.
+pub fn track(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    fetch(workspace, branch_name)?;
+
+    let repo = workspace.open()?;
+    let commit = find_tracking(workspace, &repo, branch_name)?
+        .get()
+        .peel_to_commit()
+        .git_error("Unable to find remote branch commit")?;
+
+    repo.branch(branch_name, &commit, false)
+        .git_error(&format!("Unable to create {}", branch_name))?;
+    set_upstream(workspace, branch_name)
+}
+
+//------------------------------------------------------------------------------
+pub struct Listing {
+    pub name: std::string::String,
+    pub local: bool,
+    pub remote: bool,
+    pub last_commit: git2::Time,
+}
+
+//------------------------------------------------------------------------------
+/// All the branches starting with prefix, local or on the remote.
+pub fn list(
+    workspace: &workspace::Workspace,
+    prefix: &str,
+) -> error::Result<std::vec::Vec<Listing>> {
+    fetch(workspace, &format!("{}*", prefix))?;
+
+    let repo = workspace.open()?;
+    let remote_prefix = format!("{}/", remote_name(workspace)?);
+
+    let mut listings: std::vec::Vec<Listing> = std::vec::Vec::new();
+    for branch in repo.branches(None).git_error("Unable to list branches")? {
+        let (branch, branch_type) =
+            branch.git_error("Unable to read branch")?;
+        let name = branch
+            .name()
+            .git_error("Unable to read branch name")?
+            .git_error("Branch name is not utf8")?;
+
+        let (name, remote) = match branch_type {
+            git2::BranchType::Local => (name, false),
+            git2::BranchType::Remote => match name.strip_prefix(&remote_prefix)
+            {
+                Some(name) => (name, true),
+                None => continue,
+            },
+        };
+
+        if !name.starts_with(prefix) {
+            continue;
+        }
+
+        let last_commit = branch
+            .get()
+            .peel_to_commit()
+            .git_error("Unable to find branch commit")?
+            .time();
+
+        match listings.iter_mut().find(|l| l.name == name) {
+            Some(listing) => {
+                listing.local |= !remote;
+                listing.remote |= remote;
+                if last_commit.seconds() > listing.last_commit.seconds() {
+                    listing.last_commit = last_commit;
+                }
+            }
+            None => listings.push(Listing {
+                name: name.to_string(),
+                local: !remote,
+                remote,
+                last_commit,
+            }),
+        }
+    }
+
+    listings.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(listings)
+}
+
+//------------------------------------------------------------------------------
+pub fn tip(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<git2::Oid> {
+    let repo = workspace.open()?;
+    let tip = target_oid(&find_local(&repo, branch_name)?)?;
+    Ok(tip)
 }
 
-pub fn find_remote() -> std::string::String {
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
+//------------------------------------------------------------------------------
+/// Rename a local branch, its config and stacking go along with it.
+pub fn rename(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+    new_name: &str,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+    find_local(&repo, branch_name)?
+        .rename(new_name, false)
+        .git_error(&format!(
+            "Unable to rename {} to {}",
+            branch_name, new_name
+        ))?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Put the current branch and checkout back to an earlier commit, anything
+/// since is thrown away.
+pub fn reset_hard(
+    workspace: &workspace::Workspace,
+    oid: git2::Oid,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+    let commit = repo
+        .find_object(oid, Some(git2::ObjectType::Commit))
+        .git_error("Unable to find commit to reset to")?;
+    repo.reset(&commit, git2::ResetType::Hard, None)
+        .git_error("Unable to reset")
+}
+
+//------------------------------------------------------------------------------
+pub fn exists(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<bool> {
+    let repo = workspace.open()?;
+    let exists = repo
+        .find_branch(branch_name, git2::BranchType::Local)
+        .is_ok();
+    Ok(exists)
+}
+
+//------------------------------------------------------------------------------
+pub fn switch(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    let repo = workspace.open()?;
+
+    let branch = find_local(&repo, branch_name)?;
+    let reference = branch.get();
+    let commit = reference
+        .peel_to_commit()
+        .git_error("Unable to find branch commit")?;
+
+    repo.checkout_tree(commit.as_object(), None)
+        .git_error(&format!("Unable to checkout {}", branch_name))?;
+    repo.set_head(
+        reference
+            .name()
+            .git_error("Unable to get the refname for branch")?,
+    )
+    .git_error(&format!("Unable to set HEAD to {}", branch_name))
+}
+
+//------------------------------------------------------------------------------
+/// Whether the local branch is behind its remote copy, and so can be fast
+/// forwarded. Local commits that never made it to the remote are an error.
+pub fn verify_fast_forward(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<bool> {
+    let ancestry = ancestry(workspace, branch_name, branch_name)?;
+    if ancestry.ahead > 0 {
+        return Err(error::Error::user(&format!(
+            "You have local changes on {}. You've gone rogue.",
+            branch_name
+        ))
+        .hint("Get those changes pushed through a merge request."));
+    }
+
+    Ok(ancestry.behind > 0)
+}
+
+//------------------------------------------------------------------------------
+pub fn fast_forward(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+) -> error::Result<()> {
+    if !verify_fast_forward(workspace, branch_name)? {
+        return Ok(());
+    }
+
+    let repo = workspace.open()?;
+
+    let mut local = find_local(&repo, branch_name)?;
+    let remote_oid =
+        target_oid(&find_tracking(workspace, &repo, branch_name)?)?;
+
+    // The checkout is about to be replaced, so it has to be clean
+    let is_head = local.is_head();
+    if is_head && !verify_index_empty(workspace)? {
+        return Err(error::Error::user(&format!(
+            "You have uncommited changes on {}",
+            branch_name
+        ))
+        .hint("Please stash them"));
+    }
+
+    local
+        .get_mut()
+        .set_target(remote_oid, "fast forward to remote")
+        .git_error(&format!("Unable to fast forward {}", branch_name))?;
+
+    if is_head {
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .git_error("Reset everything to head")?;
+    }
+    Ok(())
+}
+
+pub fn find_remote(
+    workspace: &workspace::Workspace,
+) -> error::Result<std::string::String> {
+    let repo = workspace.open()?;
     let remote = repo
-        .find_remote("origin")
-        .expect("Unable to find remote repo");
+        .find_remote(&remote_name(workspace)?)
+        .git_error("Unable to find remote repo")?;
 
-    remote
+    Ok(remote
         .url()
-        .expect("remote url is not valid utf8")
-        .to_string()
+        .git_error("remote url is not valid utf8")?
+        .to_string())
+}
+
+//------------------------------------------------------------------------------
+// Stash
+//------------------------------------------------------------------------------
+pub fn stash(workspace: &workspace::Workspace) -> error::Result<()> {
+    let mut repo = workspace.open()?;
+    let signature = repo.signature().git_error("Unable to obtain signature")?;
+    repo.stash_save(
+        &signature,
+        "git-me autostash",
+        Some(git2::StashFlags::INCLUDE_UNTRACKED),
+    )
+    .git_error("Unable to stash your changes")?;
+    Ok(())
 }
 
-pub fn verify_index_empty() -> bool {
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
+//------------------------------------------------------------------------------
+/// Put the last stash back. If it doesn't apply cleanly we hand back the files
+/// that conflict, and the stash is kept.
+pub fn unstash(
+    workspace: &workspace::Workspace,
+) -> error::Result<Option<std::vec::Vec<std::string::String>>> {
+    let mut repo = workspace.open()?;
+
+    // Changes in the checkout that it would overwrite stop it applying at all
+    if let Err(e) = repo.stash_apply(0, None) {
+        return Ok(Some(vec![e.message().to_string()]));
+    }
+
+    // Otherwise it can apply with conflicts, which leaves markers in the files
+    let paths = conflicts(&repo)?;
+    if !paths.is_empty() {
+        return Ok(Some(paths));
+    }
+
+    repo.stash_drop(0)
+        .git_error("Unable to drop the stash once it was applied")?;
+    Ok(None)
+}
+
+//------------------------------------------------------------------------------
+pub fn verify_index_empty(
+    workspace: &workspace::Workspace,
+) -> error::Result<bool> {
+    let repo = workspace.open()?;
 
     let mut result = true;
-    let statuses = repo.statuses(None).expect("Error getting status");
+    let statuses = repo.statuses(None).git_error("Error getting status")?;
     if !statuses.is_empty() {
         for s in statuses.iter() {
             if !s.status().is_ignored() {
@@ -419,42 +1257,419 @@ pub fn verify_index_empty() -> bool {
             }
         }
     }
-    result
+    Ok(result)
 }
 
-pub fn verify_up_to_date(base_commit: &str, name: &str) -> bool {
-    let repo =
-        git2::Repository::discover("./").expect("Unable to find git repo");
+//------------------------------------------------------------------------------
+/// How the branch compares to the remote copy of base, once that has been
+/// brought up to date.
+pub fn ancestry(
+    workspace: &workspace::Workspace,
+    branch_name: &str,
+    base: &str,
+) -> error::Result<ancestry::Ancestry> {
+    fetch(workspace, base)?;
+
+    let repo = workspace.open()?;
+    let local_oid = target_oid(&find_local(&repo, branch_name)?)?;
+    let base_oid = target_oid(&find_tracking(workspace, &repo, base)?)?;
+
+    ancestry::compare(&repo, local_oid, base_oid)
+}
 
-    let base_commit_oid = git2::Oid::from_str(base_commit)
-        .expect("Unable to convert the base commit id to an Oid");
+//------------------------------------------------------------------------------
+/// Fold the fixup! and squash! commits on a branch since it forked from base,
+/// and its changelog updates too if squash_changelog. See history::tidy.
+/// Returns true if the branch was rewritten.
+pub fn tidy(
+    workspace: &workspace::Workspace,
+    type_: &Type,
+    branch_name: &str,
+    base: &str,
+    squash_changelog: bool,
+) -> error::Result<bool> {
+    let repo = workspace.open()?;
+    let branch = find_local(&repo, branch_name)?;
+    let tip = target_oid(&branch)?;
+    let base_oid = target_oid(&find_tracking(workspace, &repo, base)?)?;
+    let fork = ancestry::compare(&repo, tip, base_oid)?
+        .merge_base
+        .git_error(&format!(
+            "{} has nothing in common with {}",
+            branch_name, base
+        ))?;
+
+    let changelog_summary =
+        changelog::commit_summary(&changelog_name(type_, branch_name));
+    let changelog_summary = if squash_changelog {
+        Some(changelog_summary.as_str())
+    } else {
+        None
+    };
 
-    let branch_commit = {
-        let b_oid = repo
-            .find_branch(name, git2::BranchType::Local)
-            .expect(&format!("Unable to find branch {}", name))
-            .get()
-            .target()
-            .expect("Unable to find reference target");
+    match history::tidy(&repo, fork, tip, changelog_summary)? {
+        Some(new_tip) => {
+            branch
+                .into_reference()
+                .set_target(new_tip, "git-me: tidy history")
+                .git_error(&format!("Unable to update {}", branch_name))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
 
-        repo.find_commit(b_oid)
-            .expect(&format!("Unable to find head commit for {}", name))
+//------------------------------------------------------------------------------
+/// True for the summaries of the commits git-me makes on a branch, which
+/// aren't held to the commit message rules.
+pub fn made_by_git_me(type_: &Type, branch_name: &str, summary: &str) -> bool {
+    summary.starts_with(&format!("Fork branch '{}' ", branch_name))
+        || summary
+            == changelog::commit_summary(&changelog_name(type_, branch_name))
+}
+
+//------------------------------------------------------------------------------
+/// Check the messages of the commits on a branch since it forked from base.
+/// The commits git-me makes itself are left out, as are fixups if they're
+/// about to be folded.
+pub fn lint(
+    workspace: &workspace::Workspace,
+    type_: &Type,
+    branch_name: &str,
+    base: &str,
+    rules: &lint::Rules,
+    final_review: bool,
+    autosquash: bool,
+) -> error::Result<std::vec::Vec<lint::Violation>> {
+    let repo = workspace.open()?;
+    let tip = target_oid(&find_local(&repo, branch_name)?)?;
+    let base_oid = target_oid(&find_tracking(workspace, &repo, base)?)?;
+
+    let mut violations = std::vec::Vec::new();
+    for commit in commits_since(&repo, tip, base_oid)? {
+        let summary = commit.summary().unwrap_or("").to_string();
+        if commit.parent_count() > 1
+            || made_by_git_me(type_, branch_name, &summary)
+            || (autosquash
+                && (summary.starts_with("fixup! ")
+                    || summary.starts_with("squash! ")))
+        {
+            continue;
+        }
+
+        let problems = rules.check(
+            commit.message().git_error("Commit message is not utf8")?,
+            final_review,
+        )?;
+        if !problems.is_empty() {
+            violations.push(lint::Violation {
+                commit: commit.id(),
+                summary,
+                problems,
+            });
+        }
+    }
+    Ok(violations)
+}
+
+//------------------------------------------------------------------------------
+/// The commits from tip back to where it forked from base, oldest first.
+fn commits_since<'r>(
+    repo: &'r git2::Repository,
+    tip: git2::Oid,
+    base: git2::Oid,
+) -> error::Result<std::vec::Vec<git2::Commit<'r>>> {
+    let mut walk = repo.revwalk().git_error("Unable to walk the history")?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)
+        .git_error("Unable to walk the history")?;
+    walk.push(tip).git_error("Unable to walk the history")?;
+    walk.hide(base).git_error("Unable to walk the history")?;
+
+    let mut commits = std::vec::Vec::new();
+    for oid in walk {
+        commits.push(
+            repo.find_commit(oid.git_error("Unable to walk the history")?)
+                .git_error("Unable to find commit")?,
+        );
+    }
+    Ok(commits)
+}
+
+//------------------------------------------------------------------------------
+/// Copy the work on a branch since it forked from base onto the remote copy
+/// of target, as the new local branch new_name. The commits git-me made are
+/// left behind, the changelog is copied over under the new name instead.
+pub fn backport(
+    workspace: &workspace::Workspace,
+    type_: &Type,
+    branch_name: &str,
+    base: &str,
+    target: &str,
+    new_name: &str,
+) -> error::Result<history::PickOutcome> {
+    fetch(workspace, base)?;
+    fetch(workspace, target)?;
+
+    let repo = workspace.open()?;
+    let tip = target_oid(&find_local(&repo, branch_name)?)?;
+    let base_oid = target_oid(&find_tracking(workspace, &repo, base)?)?;
+    let onto = target_oid(&find_tracking(workspace, &repo, target)?)?;
+
+    let mut commits = std::vec::Vec::new();
+    for commit in commits_since(&repo, tip, base_oid)? {
+        if commit.parent_count() > 1
+            || made_by_git_me(
+                type_,
+                branch_name,
+                commit.summary().unwrap_or(""),
+            )
+        {
+            continue;
+        }
+        commits.push(commit.id());
+    }
+
+    let picked = match history::pick(&repo, &commits, onto)? {
+        history::PickOutcome::Picked(picked) => picked,
+        conflicts => return Ok(conflicts),
     };
+    let mut new_tip = repo
+        .find_commit(picked)
+        .git_error("Unable to find commit")?;
+
+    // Bring the changelog over under the new name
+    let changelog_path = |name: &str| {
+        changelog::resolve(workspace, &changelog_name(type_, name))
+            .strip_prefix(workspace.path())
+            .map(std::path::Path::to_path_buf)
+            .git_error("The changelog isn't in the repo")
+    };
+    let tip_tree = repo
+        .find_commit(tip)
+        .and_then(|commit| commit.tree())
+        .git_error("Unable to find the tip's tree")?;
+    if let Ok(entry) = tip_tree.get_path(&changelog_path(branch_name)?) {
+        let tree = git2::build::TreeUpdateBuilder::new()
+            .upsert(changelog_path(new_name)?, entry.id(), git2::FileMode::Blob)
+            .create_updated(
+                &repo,
+                &new_tip.tree().git_error("Unable to find tree")?,
+            )
+            .git_error("Unable to copy the changelog")?;
+        let tree = repo.find_tree(tree).git_error("Unable to find tree")?;
+        let signature =
+            repo.signature().git_error("Unable to obtain signature")?;
+        let oid = repo
+            .commit(
+                None,
+                &signature,
+                &signature,
+                &changelog::commit_summary(&changelog_name(type_, new_name)),
+                &tree,
+                &[&new_tip],
+            )
+            .git_error("Unable to commit the changelog")?;
+        new_tip = repo.find_commit(oid).git_error("Unable to find commit")?;
+    }
+
+    repo.branch(new_name, &new_tip, false)
+        .git_error(&format!("Unable to create {}", new_name))?;
+    Ok(history::PickOutcome::Picked(new_tip.id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut current_commit = branch_commit.clone();
-    loop {
-        if current_commit.id() == base_commit_oid {
-            return true;
+    /// A repo with a checkout that's removed once the test is done with it.
+    struct Scratch {
+        path: std::path::PathBuf,
+        workspace: workspace::Workspace,
+        repo: git2::Repository,
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
         }
+    }
 
-        if current_commit.parent_count() == 0 {
-            break;
-        } else {
-            current_commit = current_commit
-                .parent(0)
-                .expect("Unable to obtain parent commit");
+    fn scratch(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "git-me-branch-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        let repo = git2::Repository::init(&path).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "git-me").unwrap();
+        config.set_str("user.email", "git-me@example.com").unwrap();
+        let workspace = workspace::Workspace::discover(&path).unwrap();
+        Scratch {
+            path,
+            workspace,
+            repo,
+        }
+    }
+
+    /// Commit files onto branch, on top of parent's files.
+    fn commit(
+        repo: &git2::Repository,
+        branch_name: &str,
+        parent: Option<git2::Oid>,
+        files: &[(&str, &str)],
+    ) -> git2::Oid {
+        let parent = parent.map(|oid| repo.find_commit(oid).unwrap());
+        let tree = match &parent {
+            Some(parent) => parent.tree().unwrap(),
+            None => {
+                let empty = repo.treebuilder(None).unwrap().write().unwrap();
+                repo.find_tree(empty).unwrap()
+            }
+        };
+
+        let mut update = git2::build::TreeUpdateBuilder::new();
+        for (path, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            update.upsert(*path, blob, git2::FileMode::Blob);
         }
+        let tree = update.create_updated(repo, &tree).unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+
+        let signature = repo.signature().unwrap();
+        let parents: std::vec::Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(
+            Some(&format!("refs/heads/{}", branch_name)),
+            &signature,
+            &signature,
+            &format!("Commit to {}", branch_name),
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    fn check_out(repo: &git2::Repository, branch_name: &str) {
+        let reference = format!("refs/heads/{}", branch_name);
+        let commit = repo.revparse_single(&reference).unwrap();
+        repo.checkout_tree(
+            &commit,
+            Some(git2::build::CheckoutBuilder::new().force()),
+        )
+        .unwrap();
+        repo.set_head(&reference).unwrap();
     }
 
-    false
+    fn type_(name: &str, base: &str) -> Type {
+        Type {
+            name: name.to_string(),
+            prefix: format!("{}/", name),
+            base: base.to_string(),
+            target: None,
+            back_merge: None,
+            naming: NAMING.to_string(),
+            changelog: None,
+            release: false,
+        }
+    }
+
+    /// The paths that changed from the commit's parent.
+    fn changed(repo: &git2::Repository, branch_name: &str) -> Vec<String> {
+        let commit = find_local(repo, branch_name)
+            .unwrap()
+            .get()
+            .peel_to_commit()
+            .unwrap();
+        let parent = commit.parent(0).unwrap();
+        let diff = repo
+            .diff_tree_to_tree(
+                Some(&parent.tree().unwrap()),
+                Some(&commit.tree().unwrap()),
+                None,
+            )
+            .unwrap();
+        diff.deltas()
+            .map(|d| d.new_file().path().unwrap().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn fork_from_a_base_that_isnt_checked_out() {
+        let s = scratch("fork");
+        let master = commit(&s.repo, "master", None, &[("a", "1")]);
+        commit(&s.repo, "develop", Some(master), &[("d", "1")]);
+        check_out(&s.repo, "develop");
+
+        let hotfix = type_("hotfix", "master");
+        branch(&s.workspace, &hotfix, "x", None, None, None).unwrap();
+
+        // Only the changelog on top of master, nothing from develop
+        let fork = find_local(&s.repo, "hotfix/x").unwrap();
+        let fork = fork.get().peel_to_commit().unwrap();
+        assert_eq!(fork.parent_id(0).unwrap(), master);
+        assert_eq!(changed(&s.repo, "hotfix/x"), ["changelog/hotfix/x.yml"]);
+        assert!(fork.tree().unwrap().get_path("d".as_ref()).is_err());
+
+        // And the checkout matches it
+        assert_eq!(s.repo.head().unwrap().shorthand(), Some("hotfix/x"));
+        assert!(!s.path.join("d").exists());
+        assert!(verify_index_empty(&s.workspace).unwrap());
+    }
+
+    #[test]
+    fn fork_on_another_branch_only_adds_the_changelog() {
+        let s = scratch("stack");
+        let develop = commit(&s.repo, "develop", None, &[("a", "1")]);
+        let a = commit(&s.repo, "feature/a", Some(develop), &[("b", "1")]);
+        check_out(&s.repo, "develop");
+
+        let feature = type_("feature", "develop");
+        branch(&s.workspace, &feature, "x", Some("feature/a"), None, None)
+            .unwrap();
+
+        let fork = find_local(&s.repo, "feature/x").unwrap();
+        let fork = fork.get().peel_to_commit().unwrap();
+        assert_eq!(fork.parent_id(0).unwrap(), a);
+        assert_eq!(changed(&s.repo, "feature/x"), ["changelog/feature/x.yml"]);
+        assert!(s.path.join("b").exists());
+    }
+
+    fn stashes(repo: &mut git2::Repository) -> usize {
+        let mut count = 0;
+        repo.stash_foreach(|_, _, _| {
+            count += 1;
+            true
+        })
+        .unwrap();
+        count
+    }
+
+    #[test]
+    fn unstash_cleanly() {
+        let mut s = scratch("unstash");
+        commit(&s.repo, "master", None, &[("f", "1\n")]);
+        check_out(&s.repo, "master");
+
+        std::fs::write(s.path.join("f"), "2\n").unwrap();
+        stash(&s.workspace).unwrap();
+        assert_eq!(unstash(&s.workspace).unwrap(), None);
+        assert_eq!(std::fs::read_to_string(s.path.join("f")).unwrap(), "2\n");
+        assert_eq!(stashes(&mut s.repo), 0);
+    }
+
+    #[test]
+    fn unstash_with_conflicts_keeps_the_stash() {
+        let mut s = scratch("unstash-conflicts");
+        let master = commit(&s.repo, "master", None, &[("f", "1\n")]);
+        check_out(&s.repo, "master");
+
+        std::fs::write(s.path.join("f"), "2\n").unwrap();
+        stash(&s.workspace).unwrap();
+        commit(&s.repo, "master", Some(master), &[("f", "3\n")]);
+        check_out(&s.repo, "master");
+
+        assert_eq!(unstash(&s.workspace).unwrap(), Some(vec!["f".to_string()]));
+        assert_eq!(stashes(&mut s.repo), 1);
+    }
 }
@@ -3,6 +3,9 @@
 //------------------------------------------------------------------------------
 const CHANGELOG: &'static str = "changelog";
 
+use crate::error;
+use crate::error::Context as _;
+use crate::workspace;
 use maplit::hashmap;
 use std::collections::HashMap;
 
@@ -68,55 +71,81 @@ fn contains_something(lines: &[String]) -> bool {
 
 //------------------------------------------------------------------------------
 pub fn resolve(
+    workspace: &workspace::Workspace,
     name: &str,
-    repo_path: Option<&std::path::Path>,
 ) -> std::path::PathBuf {
-    let mut changelog_file = if let Some(repo_path) = repo_path {
-        repo_path.join(&std::path::PathBuf::from(CHANGELOG))
-    } else {
-        std::path::PathBuf::from(CHANGELOG)
-    };
-    changelog_file.push(std::path::Path::new(name));
-    changelog_file.set_extension("yml");
-    changelog_file
+    // Appended rather than set, names can have dots in them, ie versions
+    workspace
+        .path()
+        .join(CHANGELOG)
+        .join(format!("{}.yml", name))
 }
 
 //------------------------------------------------------------------------------
 pub fn create_stub(
+    workspace: &workspace::Workspace,
     name: &str,
-    repo_path: Option<&std::path::Path>,
-) -> std::path::PathBuf {
+) -> error::Result<std::path::PathBuf> {
     // Build the changelog file path
-    let changelog_file = resolve(name, repo_path);
+    let changelog_file = resolve(workspace, name);
 
     // Make sure the owning folder exists
-    std::fs::create_dir_all(changelog_file.parent().unwrap())
-        .expect("Unable to create changlog folder");
+    create_folder(&changelog_file)?;
 
     // Write a stub changelog file to disk
-    use std::io::Write as _;
     serde_yaml::to_writer(
         std::fs::File::create(&changelog_file)
-            .expect("Unable to create changelog file"),
+            .changelog_error("Unable to create changelog file")?,
         &Changelog::new(),
     )
-    .expect("Unable to write the changlog to disk");
+    .changelog_error("Unable to write the changlog to disk")?;
+
+    Ok(changelog_file)
+}
+
+//------------------------------------------------------------------------------
+fn create_folder(changelog_file: &std::path::Path) -> error::Result<()> {
+    std::fs::create_dir_all(
+        changelog_file
+            .parent()
+            .changelog_error("Changelog has no folder")?,
+    )
+    .changelog_error("Unable to create changlog folder")
+}
+
+//------------------------------------------------------------------------------
+pub fn create_from_template(
+    workspace: &workspace::Workspace,
+    name: &str,
+    template: &std::path::Path,
+) -> error::Result<std::path::PathBuf> {
+    // Build the changelog file path
+    let changelog_file = resolve(workspace, name);
+
+    // Make sure the owning folder exists
+    create_folder(&changelog_file)?;
+
+    // Copy the template in place of the stub
+    let template = workspace.path().join(template);
+    std::fs::copy(&template, &changelog_file).changelog_error(&format!(
+        "Unable to copy changelog template {}",
+        template.display()
+    ))?;
 
-    changelog_file
+    Ok(changelog_file)
 }
 
 //------------------------------------------------------------------------------
 pub fn create_with_msg(
+    workspace: &workspace::Workspace,
     name: &str,
-    repo_path: Option<&std::path::Path>,
     msg: &str,
-) -> std::path::PathBuf {
+) -> error::Result<std::path::PathBuf> {
     // Build the changelog file path
-    let changelog_file = resolve(name, repo_path);
+    let changelog_file = resolve(workspace, name);
 
     // Make sure the owning folder exists
-    std::fs::create_dir_all(changelog_file.parent().unwrap())
-        .expect("Unable to create changlog folder");
+    create_folder(&changelog_file)?;
 
     // Change the changelog file
     let mut changelog = Changelog::new();
@@ -125,56 +154,81 @@ pub fn create_with_msg(
     };
 
     // Write a stub changelog file to disk
-    use std::io::Write as _;
     serde_yaml::to_writer(
         std::fs::File::create(&changelog_file)
-            .expect("Unable to create changelog file"),
+            .changelog_error("Unable to create changelog file")?,
         &changelog,
     )
-    .expect("Unable to write the changlog to disk");
+    .changelog_error("Unable to write the changlog to disk")?;
 
-    changelog_file
+    Ok(changelog_file)
 }
 
 //------------------------------------------------------------------------------
-pub fn verify(name: &str, repo_path: Option<&std::path::Path>) -> bool {
-    validate(&resolve(name, repo_path))
+/// Move a changelog to go with its renamed branch, and commit it.
+pub fn rename(
+    workspace: &workspace::Workspace,
+    name: &str,
+    new_name: &str,
+) -> error::Result<()> {
+    let new_path = resolve(workspace, new_name);
+    create_folder(&new_path)?;
+
+    let status = std::process::Command::new("git")
+        .arg("mv")
+        .arg(resolve(workspace, name))
+        .arg(&new_path)
+        .current_dir(workspace.path())
+        .status()
+        .git_error("failed to execute git mv")?;
+    if !status.success() {
+        return Err(error::Error::git("Unable to move the changelog"));
+    }
+
+    let status = std::process::Command::new("git")
+        .arg("commit")
+        .arg("-m")
+        .arg(commit_summary(new_name))
+        .current_dir(workspace.path())
+        .status()
+        .git_error("failed to execute git commit")?;
+    if !status.success() {
+        return Err(error::Error::git("Unable to commit the moved changelog"));
+    }
+    Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn read(path: &std::path::PathBuf) -> std::string::String {
-    use std::io::Read as _;
-    let mut changelog_file = std::fs::File::open(
-        path.to_str().expect("changelog path is not unicode"),
-    )
-    .expect(&format!(
-        "Unable to read change log file '{}'",
-        path.to_str().unwrap()
-    ));
+/// What edit commits the changelog with.
+pub fn commit_summary(name: &str) -> std::string::String {
+    format!("update changelog for {}", name)
+}
 
-    let mut contents = String::new();
-    changelog_file
-        .read_to_string(&mut contents)
-        .expect("Unable to read changelog for validation");
+//------------------------------------------------------------------------------
+pub fn verify(
+    workspace: &workspace::Workspace,
+    name: &str,
+) -> error::Result<bool> {
+    validate(&resolve(workspace, name))
+}
 
-    contents
+//------------------------------------------------------------------------------
+pub fn read(path: &std::path::Path) -> error::Result<std::string::String> {
+    std::fs::read_to_string(path).changelog_error(&format!(
+        "Unable to read change log file '{}'",
+        path.display()
+    ))
 }
 
 //------------------------------------------------------------------------------
-pub fn read_formatted(path: &std::path::PathBuf) -> std::string::String {
-    let change_log: Changelog = serde_yaml::from_reader(
-        std::fs::File::open(
-            path.to_str().expect("changelog path is not unicode"),
-        )
-        .expect(&format!(
-            "Unable to read change log file '{}'",
-            path.to_str().unwrap()
-        )),
-    )
-    .expect(&format!(
-        "Unable to parse the change log from disk '{}'",
-        path.to_str().unwrap()
-    ));
+pub fn read_formatted(
+    path: &std::path::Path,
+) -> error::Result<std::string::String> {
+    let change_log: Changelog = serde_yaml::from_str(&read(path)?)
+        .changelog_error(&format!(
+            "Unable to parse the change log from disk '{}'",
+            path.display()
+        ))?;
 
     let mut result = std::string::String::new();
 
@@ -208,55 +262,40 @@ pub fn read_formatted(path: &std::path::PathBuf) -> std::string::String {
         }
     }
 
-    result
+    Ok(result)
 }
 
 //------------------------------------------------------------------------------
-pub fn validate(path: &std::path::PathBuf) -> bool {
+/// Ok(false) means the changelog is fine, but hasn't been filled in.
+pub fn validate(path: &std::path::Path) -> error::Result<bool> {
     // Make sure no invalid characters
-    {
-        use std::io::Read as _;
-        let mut changelog_file = std::fs::File::open(
-            path.to_str().expect("changelog path is not unicode"),
-        )
-        .expect(&format!(
-            "Unable to read change log file '{}'",
-            path.to_str().unwrap()
-        ));
-
-        let mut contents = String::new();
-        changelog_file
-            .read_to_string(&mut contents)
-            .expect("Unable to read changelog for validation");
-
-        // No tabs
-        if contents.find("\t").is_some() {
-            panic!("Changelog {:?} contains tabs", path);
-        }
+    let contents = read(path)?;
+
+    // No tabs
+    if contents.contains('\t') {
+        return Err(error::Error::changelog(&format!(
+            "Changelog {:?} contains tabs",
+            path
+        )));
+    }
 
-        // Make sure it can convert to ascii
-        if !contents.is_ascii() {
-            panic!("Changelog {:?} contains non ascii characters", path);
-        }
+    // Make sure it can convert to ascii
+    if !contents.is_ascii() {
+        return Err(error::Error::changelog(&format!(
+            "Changelog {:?} contains non ascii characters",
+            path
+        )));
     }
 
     // Make sure changelog structure is correct and not empty
-    let change_log: Changelog = serde_yaml::from_reader(
-        std::fs::File::open(
-            path.to_str().expect("changelog path is not unicode"),
-        )
-        .expect(&format!(
-            "Unable to read change log file '{}'",
-            path.to_str().unwrap()
-        )),
-    )
-    .expect(&format!(
-        "Unable to parse the change log from disk '{}'",
-        path.to_str().unwrap()
-    ));
+    let change_log: Changelog = serde_yaml::from_str(&contents)
+        .changelog_error(&format!(
+            "Unable to parse the change log from disk '{}'",
+            path.display()
+        ))?;
 
     // Make sure the change log isn't empty
-    change_log.contains_entries()
+    Ok(change_log.contains_entries())
 }
 
 //------------------------------------------------------------------------------
@@ -278,65 +317,98 @@ fn merge_work(lhs: &mut Work, rhs: &Work) {
 }
 
 //------------------------------------------------------------------------------
-pub fn edit(name: &str, repo_path: Option<&std::path::Path>, commit: bool,
-            msg_from_last_commit: Option<std::string::String>) {
-    let changelog_path = resolve(name, repo_path);
+pub fn edit(workspace: &workspace::Workspace, name: &str, commit: bool,
+            msg_from_last_commit: Option<std::string::String>)
+            -> error::Result<()> {
+    let changelog_path = resolve(workspace, name);
 
     // If the changelog file doesnt exist then create a stub
     if !changelog_path.exists() {
-        create_stub(name, repo_path);
+        create_stub(workspace, name)?;
     }
 
     // If a message is provided then put it under general
     if let Some(msg) = msg_from_last_commit {
-        create_with_msg(name, repo_path, &msg);
+        create_with_msg(workspace, name, &msg)?;
     } else {
         std::process::Command::new("vi")
             .arg(&changelog_path)
             .spawn()
-            .expect("failed to execute vi")
+            .changelog_error("failed to execute vi")?
             .wait()
-            .expect("failed to wait on vi");
+            .changelog_error("failed to wait on vi")?;
     }
 
-    if !validate(&changelog_path) {
-        panic!("Changelog not valid");
+    if !validate(&changelog_path)? {
+        return Err(error::Error::changelog("Changelog not valid"));
     }
 
     std::process::Command::new("git")
         .arg("add")
         .arg(&changelog_path)
+        .current_dir(workspace.path())
         .spawn()
-        .expect("failed to execute git add")
+        .git_error("failed to execute git add")?
         .wait()
-        .expect("failed to wait on get add");
+        .git_error("failed to wait on get add")?;
 
     if commit {
-        let message = format!("update changelog for {}", name);
+        let message = commit_summary(name);
         std::process::Command::new("git")
             .arg("commit")
             .arg("-m")
             .arg(message)
+            .current_dir(workspace.path())
             .spawn()
-            .expect("failed to execute git add")
+            .git_error("failed to execute git add")?
             .wait()
-            .expect("failed to wait on get add");
+            .git_error("failed to wait on get add")?;
     }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+pub fn release_name(tag: &str) -> std::string::String {
+    tag.to_string()
 }
 
 //------------------------------------------------------------------------------
-pub fn aggregate(tag: &str, prefix: &[&str]) {
+pub fn aggregate(
+    workspace: &workspace::Workspace,
+    tag: &str,
+    prefix: &[std::string::String],
+) -> error::Result<std::path::PathBuf> {
+    // Only what's been committed counts, not files left lying around in the
+    // checkout by abandoned branches
+    let repo = workspace.open()?;
+    let head = repo
+        .head()
+        .git_error("Unable to find head")?
+        .peel_to_tree()
+        .git_error("Unable to find the tree for head")?;
+    let committed = |path: &std::path::Path| {
+        path.strip_prefix(workspace.path())
+            .is_ok_and(|path| head.get_path(path).is_ok())
+    };
+
     // Obtain a list of all the changelog files that match the given prefixes.
     // These will be aggregated and combined into a single changelog.
+    let folder = workspace.path().join(CHANGELOG);
+    let pattern =
+        format!("{}/**/*", glob::Pattern::escape(&folder.to_string_lossy()));
     let mut change_logs: std::vec::Vec<std::path::PathBuf> =
-        glob::glob(&format!("{}/**/*", &CHANGELOG))
-            .expect("Failed to read glob")
-            .filter(|e| {
-                if let Ok(entry) = e {
-                    if entry.is_file() {
-                        let file_path = entry.to_str().unwrap();
+        glob::glob(&pattern)
+            .changelog_error("Failed to read glob")?
+            .filter_map(|e| e.ok())
+            .filter(|entry| {
+                if entry.is_file() && committed(entry) {
+                    if let Some(file_path) = entry
+                        .strip_prefix(&folder)
+                        .ok()
+                        .and_then(|p| p.to_str())
+                    {
                         for p in prefix.iter() {
-                            if file_path[CHANGELOG.len() + 1..].starts_with(p) {
+                            if file_path.starts_with(p) {
                                 return true;
                             }
                         }
@@ -344,7 +416,6 @@ pub fn aggregate(tag: &str, prefix: &[&str]) {
                 }
                 false
             })
-            .map(|p| p.unwrap())
             .collect();
 
     change_logs.sort();
@@ -353,16 +424,11 @@ pub fn aggregate(tag: &str, prefix: &[&str]) {
     // Aggregate all the changelogs to produce a single one with the combined
     let mut aggregate_changelog = Changelog::empty();
     for changelog_file in change_logs.iter() {
-        let changelog: Changelog = serde_yaml::from_reader(
-            std::fs::File::open(&changelog_file).expect(&format!(
-                "Unable to open changelog file '{}'",
-                changelog_file.to_str().unwrap()
-            )),
-        )
-        .expect(&format!(
-            "Unable to read changelog file '{}'",
-            changelog_file.to_str().unwrap()
-        ));
+        let changelog: Changelog = serde_yaml::from_str(&read(changelog_file)?)
+            .changelog_error(&format!(
+                "Unable to read changelog file '{}'",
+                changelog_file.display()
+            ))?;
 
         if changelog != Changelog::new() {
             // Combine all the artists notes
@@ -390,11 +456,13 @@ pub fn aggregate(tag: &str, prefix: &[&str]) {
     }
 
     // Write the aggregate changelog to disk
-    let aggregate_changelog_path = resolve(&format!("{}.e", tag), None);
+    let aggregate_changelog_path = resolve(workspace, &release_name(tag));
     serde_yaml::to_writer(
         std::fs::File::create(&aggregate_changelog_path)
-            .expect("Unable to create aggregate changelog file"),
+            .changelog_error("Unable to create aggregate changelog file")?,
         &aggregate_changelog,
     )
-    .expect("Unable to write the aggregate changlog to disk");
+    .changelog_error("Unable to write the aggregate changlog to disk")?;
+
+    Ok(aggregate_changelog_path)
 }
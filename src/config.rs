@@ -1,6 +1,8 @@
 //------------------------------------------------------------------------------
 // from+git_me@luketitley.com
 //------------------------------------------------------------------------------
+use crate::error;
+use crate::error::Context as _;
 use std::os::unix::fs::PermissionsExt;
 
 #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -10,42 +12,44 @@ pub struct Config {
 }
 
 impl Config {
-    pub fn file_path() -> std::string::String {
+    pub fn file_path() -> error::Result<std::string::String> {
         let base_dirs = directories::BaseDirs::new()
-            .expect("Unable to obtain a list of base directories");
+            .config_error("Unable to obtain a list of base directories")?;
 
         // Return the path
-        format!(
+        Ok(format!(
             "{}/git_me.yml",
             base_dirs
                 .config_dir()
                 .to_str()
-                .expect("Unable to convert config dir path to unicode")
-        )
+                .config_error("Unable to convert config dir path to unicode")?
+        ))
     }
 
-    pub fn open() -> Self {
-        serde_yaml::from_reader(
-            std::fs::File::open(&Self::file_path())
-                .expect("Unable to create config file"),
-        )
-        .expect("Unable to read the config from disk")
+    pub fn open() -> error::Result<Self> {
+        let file_path = Self::file_path()?;
+        let config_file = std::fs::File::open(&file_path).map_err(|_| {
+            error::Error::config(&format!("Unable to open {}", file_path))
+                .hint("Run 'git me setup' to connect to your gitlab server")
+        })?;
+        serde_yaml::from_reader(config_file)
+            .config_error("Unable to read the config from disk")
     }
 
-    pub fn save(&self) {
-        let config_file = std::fs::File::create(&Self::file_path())
-            .expect("Unable to create config file");
+    pub fn save(&self) -> error::Result<()> {
+        let config_file = std::fs::File::create(&Self::file_path()?)
+            .config_error("Unable to create config file")?;
 
         let metadata = config_file
             .metadata()
-            .expect("Unable to obtain meta data for config");
+            .config_error("Unable to obtain meta data for config")?;
         let mut permissions = metadata.permissions();
         permissions.set_mode(0o600);
         config_file
             .set_permissions(permissions)
-            .expect("Unable to set permissions on config file");
+            .config_error("Unable to set permissions on config file")?;
 
         serde_yaml::to_writer(config_file, self)
-            .expect("Unable to write the config to disk");
+            .config_error("Unable to write the config to disk")
     }
 }
@@ -0,0 +1,329 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+
+//------------------------------------------------------------------------------
+/// Everything that can go wrong, grouped by who needs to do something about
+/// it. Each group exits with its own code, so scripts can tell them apart:
+///
+/// - 2 user, something to sort out first, ie uncommited changes
+/// - 3 git, the repo or the remote
+/// - 4 auth, the remote wouldn't accept any of our credentials
+/// - 5 server, talking to gitlab
+/// - 6 config, ~/.config/git_me.yml or .git-me.yml
+/// - 7 changelog, missing or invalid
+#[derive(Debug)]
+pub enum Error {
+    User {
+        message: std::string::String,
+        hint: Option<std::string::String>,
+    },
+    Git {
+        message: std::string::String,
+        cause: Option<std::string::String>,
+        hint: Option<std::string::String>,
+    },
+    Auth {
+        message: std::string::String,
+        cause: std::string::String,
+        hint: Option<std::string::String>,
+    },
+    Server {
+        message: std::string::String,
+        cause: Option<std::string::String>,
+        hint: Option<std::string::String>,
+    },
+    Config {
+        message: std::string::String,
+        cause: Option<std::string::String>,
+        hint: Option<std::string::String>,
+    },
+    Changelog {
+        message: std::string::String,
+        cause: Option<std::string::String>,
+        hint: Option<std::string::String>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn user(message: &str) -> Self {
+        Error::User {
+            message: message.to_string(),
+            hint: None,
+        }
+    }
+
+    pub fn git(message: &str) -> Self {
+        Error::Git {
+            message: message.to_string(),
+            cause: None,
+            hint: None,
+        }
+    }
+
+    pub fn server(message: &str) -> Self {
+        Error::Server {
+            message: message.to_string(),
+            cause: None,
+            hint: None,
+        }
+    }
+
+    pub fn config(message: &str) -> Self {
+        Error::Config {
+            message: message.to_string(),
+            cause: None,
+            hint: None,
+        }
+    }
+
+    pub fn changelog(message: &str) -> Self {
+        Error::Changelog {
+            message: message.to_string(),
+            cause: None,
+            hint: None,
+        }
+    }
+
+    /// Add a suggestion of what to do next.
+    pub fn hint(mut self, text: &str) -> Self {
+        match &mut self {
+            Error::User { hint, .. }
+            | Error::Git { hint, .. }
+            | Error::Auth { hint, .. }
+            | Error::Server { hint, .. }
+            | Error::Config { hint, .. }
+            | Error::Changelog { hint, .. } => *hint = Some(text.to_string()),
+        }
+        self
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::User { .. } => 2,
+            Error::Git { .. } => 3,
+            Error::Auth { .. } => 4,
+            Error::Server { .. } => 5,
+            Error::Config { .. } => 6,
+            Error::Changelog { .. } => 7,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (message, cause, hint) = match self {
+            Error::User { message, hint } => (message, None, hint.as_ref()),
+            Error::Auth {
+                message,
+                cause,
+                hint,
+            } => (message, Some(cause), hint.as_ref()),
+            Error::Git {
+                message,
+                cause,
+                hint,
+            }
+            | Error::Server {
+                message,
+                cause,
+                hint,
+            }
+            | Error::Config {
+                message,
+                cause,
+                hint,
+            }
+            | Error::Changelog {
+                message,
+                cause,
+                hint,
+            } => (message, cause.as_ref(), hint.as_ref()),
+        };
+
+        write!(f, "{}", message)?;
+        if let Some(cause) = cause {
+            write!(f, ": {}", cause)?;
+        }
+        if let Some(hint) = hint {
+            write!(f, "\n{}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+//------------------------------------------------------------------------------
+/// The errors we get back from the libraries we use.
+pub trait Cause {
+    fn describe(&self) -> std::string::String;
+
+    fn is_auth(&self) -> bool {
+        false
+    }
+}
+
+impl Cause for git2::Error {
+    fn describe(&self) -> std::string::String {
+        self.message().to_string()
+    }
+
+    fn is_auth(&self) -> bool {
+        self.code() == git2::ErrorCode::Auth
+    }
+}
+
+impl Cause for std::io::Error {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl Cause for std::env::VarError {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl Cause for std::path::StripPrefixError {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl Cause for serde_yaml::Error {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl Cause for regex::Error {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl Cause for glob::PatternError {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl Cause for reqwest::Error {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl Cause for gitlab::GitlabError {
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> Cause
+    for gitlab::api::ApiError<E>
+{
+    fn describe(&self) -> std::string::String {
+        self.to_string()
+    }
+}
+
+/// The gitlab endpoint builders fail with a plain message.
+impl Cause for std::string::String {
+    fn describe(&self) -> std::string::String {
+        self.clone()
+    }
+}
+
+/// Missing values.
+impl Cause for () {
+    fn describe(&self) -> std::string::String {
+        std::string::String::new()
+    }
+}
+
+//------------------------------------------------------------------------------
+/// Turn a library error, or a missing value, into one of ours.
+///
+/// repo.head().git_error("Unable to find head")?
+pub trait Context<T> {
+    fn git_error(self, message: &str) -> Result<T>;
+    fn server_error(self, message: &str) -> Result<T>;
+    fn config_error(self, message: &str) -> Result<T>;
+    fn changelog_error(self, message: &str) -> Result<T>;
+}
+
+fn cause(cause: &dyn Cause) -> Option<std::string::String> {
+    let description = cause.describe();
+    if description.is_empty() {
+        None
+    } else {
+        Some(description)
+    }
+}
+
+impl<T, E: Cause> Context<T> for std::result::Result<T, E> {
+    fn git_error(self, message: &str) -> Result<T> {
+        self.map_err(|e| {
+            if e.is_auth() {
+                Error::Auth {
+                    message: message.to_string(),
+                    cause: e.describe(),
+                    hint: None,
+                }
+            } else {
+                Error::Git {
+                    message: message.to_string(),
+                    cause: cause(&e),
+                    hint: None,
+                }
+            }
+        })
+    }
+
+    fn server_error(self, message: &str) -> Result<T> {
+        self.map_err(|e| Error::Server {
+            message: message.to_string(),
+            cause: cause(&e),
+            hint: None,
+        })
+    }
+
+    fn config_error(self, message: &str) -> Result<T> {
+        self.map_err(|e| Error::Config {
+            message: message.to_string(),
+            cause: cause(&e),
+            hint: None,
+        })
+    }
+
+    fn changelog_error(self, message: &str) -> Result<T> {
+        self.map_err(|e| Error::Changelog {
+            message: message.to_string(),
+            cause: cause(&e),
+            hint: None,
+        })
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn git_error(self, message: &str) -> Result<T> {
+        self.ok_or(()).git_error(message)
+    }
+
+    fn server_error(self, message: &str) -> Result<T> {
+        self.ok_or(()).server_error(message)
+    }
+
+    fn config_error(self, message: &str) -> Result<T> {
+        self.ok_or(()).config_error(message)
+    }
+
+    fn changelog_error(self, message: &str) -> Result<T> {
+        self.ok_or(()).changelog_error(message)
+    }
+}
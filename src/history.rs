@@ -0,0 +1,488 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::error;
+use crate::error::Context as _;
+
+//------------------------------------------------------------------------------
+enum Fold {
+    Fixup,
+    Squash,
+}
+
+//------------------------------------------------------------------------------
+/// A commit that stays in the history, and the ones folded into it.
+struct Group<'r> {
+    pick: git2::Commit<'r>,
+    folds: std::vec::Vec<(Fold, git2::Commit<'r>)>,
+}
+
+//------------------------------------------------------------------------------
+/// Fold the fixup! and squash! commits after base into the commits they fix,
+/// like 'git rebase --autosquash' does. If changelog_summary is given, the
+/// commits with that summary are gathered into one at the end.
+///
+/// The new commits are made without touching the checkout or any refs, so if
+/// anything doesn't replay cleanly nothing has changed. Returns the new tip,
+/// or None if there was nothing to tidy.
+pub fn tidy(
+    repo: &git2::Repository,
+    base: git2::Oid,
+    tip: git2::Oid,
+    changelog_summary: Option<&str>,
+) -> error::Result<Option<git2::Oid>> {
+    let commits = commits(repo, base, tip)?;
+    let is_changelog = |commit: &git2::Commit| {
+        changelog_summary.is_some() && commit.summary() == changelog_summary
+    };
+    let gather = commits.iter().filter(|c| is_changelog(c)).count() > 1;
+
+    let mut groups: std::vec::Vec<Group> = std::vec::Vec::new();
+    let mut changelog = std::vec::Vec::new();
+    let mut folded = gather;
+    for commit in commits {
+        if gather && is_changelog(&commit) {
+            changelog.push(commit);
+            continue;
+        }
+
+        if let Some((fold, target)) = fold_target(commit.summary()) {
+            if let Some(group) =
+                groups.iter_mut().find(|g| fixes(&g.pick, target))
+            {
+                group.folds.push((fold, commit));
+                folded = true;
+                continue;
+            }
+        }
+
+        groups.push(Group {
+            pick: commit,
+            folds: std::vec::Vec::new(),
+        });
+    }
+
+    if !folded {
+        return Ok(None);
+    }
+
+    let mut changelog = changelog.into_iter();
+    if let Some(pick) = changelog.next() {
+        groups.push(Group {
+            pick,
+            folds: changelog.map(|c| (Fold::Fixup, c)).collect(),
+        });
+    }
+
+    // Replay each group on top of the last
+    let mut parent = repo
+        .find_commit(base)
+        .git_error("Unable to find the base commit")?;
+    for group in groups {
+        let author = group.pick.author();
+        let mut message = group
+            .pick
+            .message()
+            .git_error("Commit message is not utf8")?
+            .to_string();
+        let mut commit =
+            replay(repo, &group.pick, &parent, &parent, &author, &message)?;
+
+        for (fold, fixup) in group.folds {
+            if let Fold::Squash = fold {
+                message = squash_message(&message, &fixup)?;
+            }
+            commit = replay(repo, &fixup, &commit, &parent, &author, &message)?;
+        }
+        parent = commit;
+    }
+
+    // Folding and gathering move changes around, but shouldn't change them
+    let tip_tree = repo
+        .find_commit(tip)
+        .git_error("Unable to find the tip commit")?
+        .tree_id();
+    if parent.tree_id() != tip_tree {
+        return Err(error::Error::git(
+            "The tidied history doesn't end up with the same files",
+        )
+        .hint("Tidy it up with 'git rebase -i' instead"));
+    }
+
+    Ok(Some(parent.id()))
+}
+
+//------------------------------------------------------------------------------
+/// The commits after base, oldest first.
+fn commits<'r>(
+    repo: &'r git2::Repository,
+    base: git2::Oid,
+    tip: git2::Oid,
+) -> error::Result<std::vec::Vec<git2::Commit<'r>>> {
+    let mut walk = repo.revwalk().git_error("Unable to walk the history")?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)
+        .git_error("Unable to walk the history")?;
+    walk.push(tip).git_error("Unable to walk the history")?;
+    walk.hide(base).git_error("Unable to walk the history")?;
+
+    let mut commits = std::vec::Vec::new();
+    for oid in walk {
+        let commit = repo
+            .find_commit(oid.git_error("Unable to walk the history")?)
+            .git_error("Unable to find commit")?;
+        if commit.parent_count() > 1 {
+            return Err(error::Error::user(&format!(
+                "There's a merge in the history, {}",
+                commit.id()
+            ))
+            .hint("Tidy it up with 'git rebase -i' instead"));
+        }
+        commits.push(commit);
+    }
+    Ok(commits)
+}
+
+//------------------------------------------------------------------------------
+/// The summary of the commit a fixup! or squash! commit is for.
+fn fold_target(summary: Option<&str>) -> Option<(Fold, &str)> {
+    let summary = summary?;
+    let (fold, mut target) = if let Some(t) = summary.strip_prefix("fixup! ") {
+        (Fold::Fixup, t)
+    } else if let Some(t) = summary.strip_prefix("squash! ") {
+        (Fold::Squash, t)
+    } else {
+        return None;
+    };
+
+    // A fixup of a fixup goes into the same commit
+    while let Some(t) = target
+        .strip_prefix("fixup! ")
+        .or_else(|| target.strip_prefix("squash! "))
+    {
+        target = t;
+    }
+    Some((fold, target))
+}
+
+//------------------------------------------------------------------------------
+fn fixes(commit: &git2::Commit, target: &str) -> bool {
+    commit.summary().is_some_and(|s| s.starts_with(target))
+        || (target.len() >= 4 && commit.id().to_string().starts_with(target))
+}
+
+//------------------------------------------------------------------------------
+/// Add the body of a squash! commit to message, its summary just names the
+/// commit it's for.
+fn squash_message(
+    message: &str,
+    squash: &git2::Commit,
+) -> error::Result<std::string::String> {
+    let body = squash
+        .message()
+        .git_error("Commit message is not utf8")?
+        .split_once('\n')
+        .map_or("", |(_, body)| body)
+        .trim();
+
+    if body.is_empty() {
+        Ok(message.to_string())
+    } else {
+        Ok(format!("{}\n\n{}\n", message.trim_end(), body))
+    }
+}
+
+//------------------------------------------------------------------------------
+/// Apply commit's changes on top of onto, and commit the result on parent.
+fn replay<'r>(
+    repo: &'r git2::Repository,
+    commit: &git2::Commit,
+    onto: &git2::Commit,
+    parent: &git2::Commit,
+    author: &git2::Signature,
+    message: &str,
+) -> error::Result<git2::Commit<'r>> {
+    let mut index = repo
+        .cherrypick_commit(commit, onto, 0, None)
+        .git_error("Unable to replay commit")?;
+    if index.has_conflicts() {
+        return Err(error::Error::user(&format!(
+            "{} doesn't replay cleanly, {}",
+            commit.id(),
+            commit.summary().unwrap_or("")
+        ))
+        .hint("Tidy it up with 'git rebase -i' instead"));
+    }
+
+    commit_index(repo, &mut index, parent, author, message)
+}
+
+//------------------------------------------------------------------------------
+/// How copying commits onto another branch went.
+pub enum PickOutcome {
+    /// The new tip
+    Picked(git2::Oid),
+    /// The commit that didn't apply, and the paths that conflicted
+    Conflicts(git2::Oid, std::vec::Vec<std::string::String>),
+}
+
+//------------------------------------------------------------------------------
+/// Copy commits, oldest first, onto another commit like 'git cherry-pick -x'
+/// does. As with tidy, only new commits are made, nothing else changes.
+pub fn pick(
+    repo: &git2::Repository,
+    commits: &[git2::Oid],
+    onto: git2::Oid,
+) -> error::Result<PickOutcome> {
+    let mut tip = repo
+        .find_commit(onto)
+        .git_error("Unable to find the commit to pick onto")?;
+    for oid in commits {
+        let commit =
+            repo.find_commit(*oid).git_error("Unable to find commit")?;
+        let mut index = repo
+            .cherrypick_commit(&commit, &tip, 0, None)
+            .git_error("Unable to pick commit")?;
+        if index.has_conflicts() {
+            return Ok(PickOutcome::Conflicts(*oid, conflicts(&index)?));
+        }
+
+        let message = format!(
+            "{}\n\n(cherry picked from commit {})\n",
+            commit
+                .message()
+                .git_error("Commit message is not utf8")?
+                .trim_end(),
+            oid
+        );
+        tip = commit_index(repo, &mut index, &tip, &commit.author(), &message)?;
+    }
+    Ok(PickOutcome::Picked(tip.id()))
+}
+
+//------------------------------------------------------------------------------
+/// The paths with conflicts in index.
+pub fn conflicts(
+    index: &git2::Index,
+) -> error::Result<std::vec::Vec<std::string::String>> {
+    let mut paths = std::vec::Vec::new();
+    if index.has_conflicts() {
+        for conflict in
+            index.conflicts().git_error("Unable to read conflicts")?
+        {
+            let conflict = conflict.git_error("Unable to read conflict")?;
+            let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+            if let Some(entry) = entry {
+                paths.push(
+                    std::string::String::from_utf8_lossy(&entry.path)
+                        .to_string(),
+                );
+            }
+        }
+    }
+    Ok(paths)
+}
+
+//------------------------------------------------------------------------------
+/// Commit what's in index on parent.
+fn commit_index<'r>(
+    repo: &'r git2::Repository,
+    index: &mut git2::Index,
+    parent: &git2::Commit,
+    author: &git2::Signature,
+    message: &str,
+) -> error::Result<git2::Commit<'r>> {
+    let tree = index
+        .write_tree_to(repo)
+        .git_error("Unable to write the replayed tree")?;
+    let tree = repo.find_tree(tree).git_error("Unable to find tree")?;
+    let committer = repo.signature().git_error("Unable to obtain signature")?;
+    let oid = repo
+        .commit(None, author, &committer, message, &tree, &[parent])
+        .git_error("Unable to commit")?;
+    repo.find_commit(oid).git_error("Unable to find commit")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bare repo that's removed once the test is done with it.
+    struct Scratch {
+        path: std::path::PathBuf,
+        repo: git2::Repository,
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn scratch(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "git-me-history-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        let repo = git2::Repository::init_bare(&path).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "git-me").unwrap();
+        config.set_str("user.email", "git-me@example.com").unwrap();
+        Scratch { path, repo }
+    }
+
+    /// Commit files on top of parents, the first parent's files are kept.
+    fn commit(
+        repo: &git2::Repository,
+        parents: &[git2::Oid],
+        files: &[(&str, &str)],
+        message: &str,
+    ) -> git2::Oid {
+        let parents: std::vec::Vec<git2::Commit> = parents
+            .iter()
+            .map(|oid| repo.find_commit(*oid).unwrap())
+            .collect();
+        let tree = match parents.first() {
+            Some(parent) => parent.tree().unwrap(),
+            None => {
+                let empty = repo.treebuilder(None).unwrap().write().unwrap();
+                repo.find_tree(empty).unwrap()
+            }
+        };
+
+        let mut update = git2::build::TreeUpdateBuilder::new();
+        for (path, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            update.upsert(*path, blob, git2::FileMode::Blob);
+        }
+        let tree = update.create_updated(repo, &tree).unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+
+        let signature = git2::Signature::now("t", "t@example.com").unwrap();
+        let parents: std::vec::Vec<&git2::Commit> = parents.iter().collect();
+        repo.commit(None, &signature, &signature, message, &tree, &parents)
+            .unwrap()
+    }
+
+    fn messages(
+        repo: &git2::Repository,
+        base: git2::Oid,
+        tip: git2::Oid,
+    ) -> std::vec::Vec<std::string::String> {
+        commits(repo, base, tip)
+            .unwrap()
+            .iter()
+            .map(|c| c.message().unwrap().to_string())
+            .collect()
+    }
+
+    fn tree(repo: &git2::Repository, oid: git2::Oid) -> git2::Oid {
+        repo.find_commit(oid).unwrap().tree_id()
+    }
+
+    #[test]
+    fn fixup_is_folded() {
+        let s = scratch("fixup");
+        let base = commit(&s.repo, &[], &[("a", "0")], "Base");
+        let a = commit(&s.repo, &[base], &[("a", "1")], "Change a");
+        let b = commit(&s.repo, &[a], &[("b", "1")], "Add b");
+        let tip = commit(&s.repo, &[b], &[("a", "2")], "fixup! Change a");
+
+        let new_tip = tidy(&s.repo, base, tip, None).unwrap().unwrap();
+        assert_eq!(messages(&s.repo, base, new_tip), ["Change a", "Add b"]);
+        assert_eq!(tree(&s.repo, new_tip), tree(&s.repo, tip));
+    }
+
+    #[test]
+    fn squash_keeps_its_body() {
+        let s = scratch("squash");
+        let base = commit(&s.repo, &[], &[("a", "0")], "Base");
+        let a = commit(&s.repo, &[base], &[("a", "1")], "Change a\n");
+        let tip = commit(
+            &s.repo,
+            &[a],
+            &[("a", "2")],
+            "squash! Change a\n\nAnd why\n",
+        );
+
+        let new_tip = tidy(&s.repo, base, tip, None).unwrap().unwrap();
+        assert_eq!(messages(&s.repo, base, new_tip), ["Change a\n\nAnd why\n"]);
+        assert_eq!(tree(&s.repo, new_tip), tree(&s.repo, tip));
+    }
+
+    #[test]
+    fn fixup_of_a_fixup_goes_into_the_same_commit() {
+        let s = scratch("fixup-fixup");
+        let base = commit(&s.repo, &[], &[("a", "0")], "Base");
+        let a = commit(&s.repo, &[base], &[("a", "1")], "Change a");
+        let b = commit(&s.repo, &[a], &[("b", "1")], "Add b");
+        let c = commit(&s.repo, &[b], &[("a", "2")], "fixup! Change a");
+        let tip =
+            commit(&s.repo, &[c], &[("a", "3")], "fixup! fixup! Change a");
+
+        let new_tip = tidy(&s.repo, base, tip, None).unwrap().unwrap();
+        assert_eq!(messages(&s.repo, base, new_tip), ["Change a", "Add b"]);
+        assert_eq!(tree(&s.repo, new_tip), tree(&s.repo, tip));
+    }
+
+    #[test]
+    fn changelog_updates_are_gathered_at_the_end() {
+        let summary = "update changelog for feature/x";
+        let s = scratch("changelog");
+        let base = commit(&s.repo, &[], &[("a", "0")], "Base");
+        let a = commit(&s.repo, &[base], &[("c", "1")], summary);
+        let b = commit(&s.repo, &[a], &[("a", "1")], "Change a");
+        let c = commit(&s.repo, &[b], &[("c", "2")], summary);
+        let tip = commit(&s.repo, &[c], &[("b", "1")], "Add b");
+
+        let new_tip = tidy(&s.repo, base, tip, Some(summary)).unwrap().unwrap();
+        assert_eq!(
+            messages(&s.repo, base, new_tip),
+            ["Change a", "Add b", summary]
+        );
+        assert_eq!(tree(&s.repo, new_tip), tree(&s.repo, tip));
+
+        // A single update is left where it is
+        let tip = commit(&s.repo, &[b], &[("b", "1")], "Add b");
+        assert_eq!(tidy(&s.repo, base, tip, Some(summary)).unwrap(), None);
+    }
+
+    #[test]
+    fn nothing_to_tidy() {
+        let s = scratch("nothing");
+        let base = commit(&s.repo, &[], &[("a", "0")], "Base");
+        let tip = commit(&s.repo, &[base], &[("a", "1")], "Change a");
+        assert_eq!(tidy(&s.repo, base, tip, None).unwrap(), None);
+    }
+
+    #[test]
+    fn a_different_tree_is_an_error() {
+        // The fixup undoes b, which it can't do from under it
+        let s = scratch("mismatch");
+        let base = commit(&s.repo, &[], &[("a", "0")], "Base");
+        let a = commit(&s.repo, &[base], &[("f", "x\n")], "Add f");
+        let b = commit(&s.repo, &[a], &[("f", "y\n")], "Change f");
+        let tip = commit(&s.repo, &[b], &[("f", "x\n")], "fixup! Add f");
+
+        match tidy(&s.repo, base, tip, None) {
+            Err(error::Error::Git { hint: Some(_), .. }) => (),
+            other => panic!("Expected a git error with a hint, {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_merge_is_an_error() {
+        let s = scratch("merge");
+        let base = commit(&s.repo, &[], &[("a", "0")], "Base");
+        let a = commit(&s.repo, &[base], &[("a", "1")], "Change a");
+        let b = commit(&s.repo, &[base], &[("b", "1")], "Add b");
+        let merge = commit(&s.repo, &[a, b], &[("b", "1")], "Merge");
+        let tip = commit(&s.repo, &[merge], &[("a", "2")], "fixup! Change a");
+
+        match tidy(&s.repo, base, tip, None) {
+            Err(error::Error::User { .. }) => (),
+            other => panic!("Expected a user error, {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+
+//! The git-me workflow as a library, so other tools can drive it.
+//!
+//! Nothing here goes by the current directory. Find the checkout you want to
+//! work on, then hand it to the tasks:
+//!
+//! let workspace = workspace::Workspace::discover(path)?;
+//! tasks::work::review(&workspace, vec![], false, false, false, false, false)?;
+pub mod ancestry;
+mod auth;
+pub mod branch;
+pub mod changelog;
+pub mod config;
+pub mod error;
+pub mod history;
+pub mod lint;
+mod plan;
+pub mod server;
+pub mod tasks;
+mod teams;
+pub mod workflow;
+pub mod workspace;
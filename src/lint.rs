@@ -0,0 +1,223 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::error;
+use crate::error::Context as _;
+
+//------------------------------------------------------------------------------
+/// What commit messages are held to, set under commits in .git-me.yml:
+///
+/// commits:
+///   max_subject: 72
+///   issue: '#[0-9]+'
+///   conventional: [feat, fix, docs, refactor, test, chore]
+///
+/// Each rule is off unless it's set. WIP and fixup commits are always turned
+/// away at the final review, whatever the rules.
+#[derive(
+    Debug, PartialEq, serde::Serialize, serde::Deserialize, Default, Clone,
+)]
+pub struct Rules {
+    /// The longest the summary line can be
+    #[serde(default)]
+    pub max_subject: Option<usize>,
+    /// A pattern the message has to reference an issue with
+    #[serde(default)]
+    pub issue: Option<std::string::String>,
+    /// The Conventional Commits types the summary can start with, ie 'fix: '
+    #[serde(default)]
+    pub conventional: std::vec::Vec<std::string::String>,
+}
+
+//------------------------------------------------------------------------------
+/// A commit that breaks the rules, and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub commit: git2::Oid,
+    pub summary: std::string::String,
+    pub problems: std::vec::Vec<std::string::String>,
+}
+
+impl Rules {
+    /// What's wrong with message, empty if it's fine.
+    pub fn check(
+        &self,
+        message: &str,
+        final_review: bool,
+    ) -> error::Result<std::vec::Vec<std::string::String>> {
+        let summary = message.lines().next().unwrap_or("");
+        let mut problems = std::vec::Vec::new();
+
+        if final_review {
+            let wip = regex::Regex::new(r"(?i)^(\[wip\]|wip\b)")
+                .expect("Unable to compile regex");
+            if wip.is_match(summary) {
+                problems.push("Still marked as WIP".to_string());
+            }
+        }
+
+        // Fixups get folded away, the other rules are for what they fix
+        if ["fixup! ", "squash! ", "amend! "]
+            .iter()
+            .any(|p| summary.starts_with(p))
+        {
+            if final_review {
+                problems
+                    .push("Needs folding into the commit it fixes".to_string());
+            }
+            return Ok(problems);
+        }
+
+        if let Some(max_subject) = self.max_subject {
+            let len = summary.chars().count();
+            if len > max_subject {
+                problems.push(format!(
+                    "Summary is {} characters, the limit is {}",
+                    len, max_subject
+                ));
+            }
+        }
+
+        if let Some(issue) = &self.issue {
+            let issue = regex::Regex::new(issue)
+                .config_error("Invalid issue pattern for commits")?;
+            if !issue.is_match(message) {
+                problems.push(format!(
+                    "Doesn't reference an issue, ie '{}'",
+                    issue
+                ));
+            }
+        }
+
+        if !self.conventional.is_empty() {
+            let types = self
+                .conventional
+                .iter()
+                .map(|t| regex::escape(t))
+                .collect::<std::vec::Vec<_>>()
+                .join("|");
+            let conventional =
+                regex::Regex::new(&format!(r"^({})(\([^)]+\))?!?: \S", types))
+                    .config_error("Invalid conventional types for commits")?;
+            if !conventional.is_match(summary) {
+                problems.push(format!(
+                    "Summary should start with a type, ie '{}: '",
+                    self.conventional[0]
+                ));
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Rules {
+        Rules {
+            max_subject: Some(20),
+            issue: Some("#[0-9]+".to_string()),
+            conventional: vec!["feat".to_string(), "fix".to_string()],
+        }
+    }
+
+    #[test]
+    fn no_rules_pass_anything() {
+        let rules = Rules::default();
+        assert!(rules.check("anything at all", false).unwrap().is_empty());
+        assert!(rules.check("", true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn following_the_rules() {
+        let problems = rules().check("fix: crash\n\nSee #12", true).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn subject_length() {
+        let rules = Rules {
+            max_subject: Some(10),
+            ..Rules::default()
+        };
+        assert!(rules.check("0123456789", false).unwrap().is_empty());
+        assert_eq!(rules.check("0123456789a", false).unwrap().len(), 1);
+
+        // Counted in characters, not bytes
+        assert!(rules.check("ééééééééé", false).unwrap().is_empty());
+
+        // Only the summary counts
+        let message = "short\n\nThis line is much longer than ten";
+        assert!(rules.check(message, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn issue_pattern() {
+        let rules = Rules {
+            issue: Some("#[0-9]+".to_string()),
+            ..Rules::default()
+        };
+        assert!(rules.check("Fix #3", false).unwrap().is_empty());
+        assert!(rules.check("Fix\n\nCloses #3", false).unwrap().is_empty());
+        assert_eq!(rules.check("Fix it", false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn invalid_issue_pattern() {
+        let rules = Rules {
+            issue: Some("(".to_string()),
+            ..Rules::default()
+        };
+        match rules.check("Fix #3", false) {
+            Err(error::Error::Config { .. }) => (),
+            other => panic!("Expected a config error, {:?}", other),
+        }
+    }
+
+    #[test]
+    fn conventional_commits() {
+        let rules = Rules {
+            conventional: vec!["feat".to_string(), "fix".to_string()],
+            ..Rules::default()
+        };
+        let ok = |m| rules.check(m, false).unwrap().is_empty();
+        assert!(ok("feat: add it"));
+        assert!(ok("fix(parser): handle it"));
+        assert!(ok("feat!: break it"));
+        assert!(ok("feat(api)!: break it"));
+
+        assert!(!ok("docs: not a type"));
+        assert!(!ok("feat add it"));
+        assert!(!ok("feat:add it"));
+        assert!(!ok("feat: "));
+        assert!(!ok("feat(): empty scope"));
+        assert!(!ok("feature: prefix of a type"));
+        assert!(!ok("Fix: case matters"));
+    }
+
+    #[test]
+    fn wip_only_at_the_final_review() {
+        let rules = Rules::default();
+        for message in ["WIP: thing", "wip thing", "[WIP] thing"] {
+            assert!(rules.check(message, false).unwrap().is_empty());
+            assert_eq!(rules.check(message, true).unwrap().len(), 1);
+        }
+
+        // A word that starts with wip isn't one
+        assert!(rules.check("wipe the cache", true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fixups_only_need_folding() {
+        for message in ["fixup! thing", "squash! thing", "amend! thing"] {
+            // The other rules are for the commit they fix
+            assert!(rules().check(message, false).unwrap().is_empty());
+            assert_eq!(
+                rules().check(message, true).unwrap(),
+                ["Needs folding into the commit it fixes"]
+            );
+        }
+    }
+}
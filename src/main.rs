@@ -2,72 +2,172 @@
 // from+git_me@luketitley.com
 //------------------------------------------------------------------------------
 mod args;
-mod branch;
-mod changelog;
-mod config;
-mod server;
-mod tasks;
-mod teams;
 
 use args::*;
+use git_me::{error, server, tasks, workflow, workspace};
+
+//------------------------------------------------------------------------------
+/// The checkout we were run from.
+fn current() -> error::Result<workspace::Workspace> {
+    workspace::Workspace::discover(std::path::Path::new("."))
+}
+
+//------------------------------------------------------------------------------
+fn work(type_name: &str, status: Status, dry_run: bool) -> error::Result<()> {
+    let workspace = current()?;
+    let branch_type = &workflow::Workflow::open(&workspace)?.find(type_name)?;
+
+    match status {
+        Status::Start(Start {
+            name,
+            issue,
+            on,
+            autostash,
+            worktree,
+        }) => tasks::work::start(
+            &workspace,
+            branch_type,
+            name.as_deref(),
+            issue,
+            on.as_deref(),
+            autostash,
+            worktree,
+            dry_run,
+        ),
+        Status::Rename(Rename { name }) => {
+            tasks::work::rename(&workspace, branch_type, &name, dry_run)
+        }
+        Status::Abandon(Abandon { comment, yes }) => tasks::work::abandon(
+            &workspace,
+            branch_type,
+            comment.as_deref(),
+            yes,
+            dry_run,
+        ),
+        Status::Checkout(Checkout { name }) => {
+            tasks::work::checkout(&workspace, branch_type, &name, dry_run)
+        }
+        Status::Backport(Backport { to }) => {
+            tasks::work::backport(&workspace, branch_type, &to, dry_run)
+        }
+        Status::Rebase(Rebase { continue_, abort }) => tasks::work::rebase(
+            &workspace,
+            branch_type,
+            continue_,
+            abort,
+            dry_run,
+        ),
+        Status::Finish(Finish {}) => {
+            tasks::work::finish(&workspace, branch_type, dry_run)
+        }
+        Status::List(List { all }) => {
+            tasks::work::list(&workspace, branch_type, all)
+        }
+        Status::Switch(Switch { name }) => {
+            tasks::work::switch(&workspace, branch_type, &name, dry_run)
+        }
+    }
+}
 
 //------------------------------------------------------------------------------
 fn main() {
     let tasks: Tasks = argh::from_env();
+    if let Err(error) = run(tasks) {
+        eprintln!("{}", error);
+        std::process::exit(error.exit_code());
+    }
+}
+
+//------------------------------------------------------------------------------
+fn run(tasks: Tasks) -> error::Result<()> {
+    let dry_run = tasks.dry_run;
 
     match tasks.task {
-        // Feature
-        Task::Feature(Feature { status }) => match status {
-            Status::Start(Start { name }) => {
-                tasks::work::start(branch::Type::Feature, &name)
-            }
-            Status::Rebase(Rebase {}) => {
-                println!("wip");
-                //println!("try: 'git checkout '");
-                //tasks::work::rebase(branch::Type::Feature)
-            }
-        },
-        // Hotfix
-        Task::Hotfix(Hotfix { status }) => match status {
-            Status::Start(Start { name }) => {
-                tasks::work::start(branch::Type::Hotfix, &name)
+        Task::Feature(Feature { status }) => work("feature", status, dry_run),
+        Task::Hotfix(Hotfix { status }) => work("hotfix", status, dry_run),
+        Task::Work(Work { type_, status }) => work(&type_, status, dry_run),
+        // Release
+        Task::Release(Release { status }) => {
+            let workspace = current()?;
+            let release =
+                workflow::Workflow::open(&workspace)?.find("release")?;
+            match status {
+                ReleaseStatus::Start(ReleaseStart { version }) => {
+                    tasks::work::start(
+                        &workspace,
+                        &release,
+                        Some(&version),
+                        None,
+                        None,
+                        false,
+                        false,
+                        dry_run,
+                    )
+                }
+                ReleaseStatus::Finish(Finish {}) => {
+                    tasks::work::finish(&workspace, &release, dry_run)
+                }
             }
-            Status::Rebase(Rebase {}) => {
-                println!("wip");
-                //tasks::work::rebase(branch::Type::Hotfix)
-            }
-        },
-        Task::Review(Review { finished }) => tasks::work::review(finished),
+        }
+        Task::Review(Review {
+            finished,
+            autostash,
+            autosquash,
+            squash_changelog,
+            override_rules,
+        }) => tasks::work::review(
+            &current()?,
+            finished,
+            autostash,
+            autosquash,
+            squash_changelog,
+            override_rules,
+            dry_run,
+        ),
+        Task::Status(Overview {}) => tasks::status::status(&current()?),
+        Task::Sync(Synchronise { yes }) => {
+            tasks::sync::sync(&current()?, yes, dry_run)
+        }
+        Task::Worktree(Worktree {
+            command: WorktreeCommand::List(WorktreeList {}),
+        }) => tasks::worktree::list(&current()?),
+        Task::Worktree(Worktree {
+            command: WorktreeCommand::Remove(WorktreeRemove { name, force }),
+        }) => tasks::worktree::remove(&current()?, &name, force, dry_run),
+        Task::Hooks(Hooks {
+            command: HooksCommand::Install(HooksInstall {}),
+        }) => tasks::hooks::install(&current()?, dry_run),
+        Task::Hooks(Hooks {
+            command: HooksCommand::Uninstall(HooksUninstall {}),
+        }) => tasks::hooks::uninstall(&current()?, dry_run),
+        Task::Hooks(Hooks {
+            command: HooksCommand::Run(HooksRun { hook, args }),
+        }) => tasks::hooks::run(&current()?, &hook, &args),
         Task::Changelog(Changelog {
             status: ChangelogStatus::Aggregate(Aggregate { tag }),
-        }) => {
-            tasks::changelog::aggregate(&tag);
-        }
+        }) => tasks::changelog::aggregate(&current()?, &tag, dry_run),
         Task::Changelog(Changelog {
             status: ChangelogStatus::Validate(Validate { path }),
-        }) => {
-            tasks::changelog::validate(&path);
-        }
+        }) => tasks::changelog::validate(&path),
         Task::Changelog(Changelog {
             status: ChangelogStatus::Edit(Edit { commit, last_commit }),
-        }) => {
-            tasks::changelog::edit(commit, last_commit);
-        }
+        }) => tasks::changelog::edit(
+            &current()?,
+            commit,
+            last_commit,
+            dry_run,
+        ),
         Task::Setup(Setup {
             server,
             private_token,
-        }) => {
-            tasks::setup::setup(&server, &private_token);
-        }
-        Task::Info(Info {}) => {
-            tasks::setup::info();
-        }
+        }) => tasks::setup::setup(&server, &private_token),
+        Task::Info(Info {}) => tasks::setup::info(),
         Task::Project(Project {
             project: ProjectCommand::List(list),
         }) => {
-            let sv = server::Server::new();
-            sv.list_projects(|project| println!("{}", project.ssh_url_to_repo));
+            let sv = server::Server::new()?;
+            sv.list_projects(|project| println!("{}", project.ssh_url_to_repo))
         }
-        _ => (),
+        _ => Ok(()),
     }
 }
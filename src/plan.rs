@@ -0,0 +1,124 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::error;
+
+//------------------------------------------------------------------------------
+/// Whether the rest of the plan should go ahead once a step has run.
+pub enum Outcome {
+    Continue,
+    Stop,
+}
+
+type Undo = Box<dyn FnOnce() -> error::Result<()>>;
+
+//------------------------------------------------------------------------------
+struct Step {
+    description: std::string::String,
+    action: Box<dyn FnOnce() -> error::Result<Outcome>>,
+    undo: Option<Undo>,
+}
+
+//------------------------------------------------------------------------------
+/// Everything a task is going to do to the branches, the remote and gitlab.
+///
+/// Tasks do their checks and queries upfront, then add a step for each change
+/// they want to make. The plan is either run, or with --dry-run just printed,
+/// so nothing shared is touched until the whole thing has been worked out.
+///
+/// If a step fails, the steps that have already run are undone in reverse,
+/// where they know how.
+pub struct Plan {
+    steps: std::vec::Vec<Step>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Plan { steps: std::vec::Vec::new() }
+    }
+
+    pub fn add<F>(&mut self, description: &str, action: F)
+    where
+        F: FnOnce() -> error::Result<()> + 'static,
+    {
+        self.add_step(description, move || {
+            action()?;
+            Ok(Outcome::Continue)
+        });
+    }
+
+    /// Add a step that can stop the rest of the plan, ie on rebase conflicts.
+    pub fn add_step<F>(&mut self, description: &str, action: F)
+    where
+        F: FnOnce() -> error::Result<Outcome> + 'static,
+    {
+        self.steps.push(Step {
+            description: description.to_string(),
+            action: Box::new(action),
+            undo: None,
+        });
+    }
+
+    /// Add a step that can be rolled back if a later one fails.
+    pub fn add_with_undo<F, U>(&mut self, description: &str, action: F, undo: U)
+    where
+        F: FnOnce() -> error::Result<()> + 'static,
+        U: FnOnce() -> error::Result<()> + 'static,
+    {
+        self.steps.push(Step {
+            description: description.to_string(),
+            action: Box::new(move || {
+                action()?;
+                Ok(Outcome::Continue)
+            }),
+            undo: Some(Box::new(undo)),
+        });
+    }
+
+    /// Returns true if every step ran.
+    pub fn run(self, dry_run: bool) -> error::Result<bool> {
+        if dry_run {
+            println!("Dry run, nothing has been changed. git-me would:");
+            for step in self.steps.iter() {
+                println!("    * {}", step.description);
+            }
+            return Ok(false);
+        }
+
+        let mut done = std::vec::Vec::new();
+        for step in self.steps.into_iter() {
+            println!("    * {}", step.description);
+            match (step.action)() {
+                Ok(Outcome::Continue) => {
+                    done.push((step.description, step.undo))
+                }
+                Ok(Outcome::Stop) => return Ok(false),
+                Err(error) => {
+                    rollback(done);
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+//------------------------------------------------------------------------------
+fn rollback(done: std::vec::Vec<(std::string::String, Option<Undo>)>) {
+    if done.iter().all(|(_, undo)| undo.is_none()) {
+        return;
+    }
+
+    println!("Rolling back");
+    for (description, undo) in done.into_iter().rev() {
+        if let Some(undo) = undo {
+            println!("    * Undo {}", description);
+
+            // Keep going, so as much as possible is put back
+            if let Err(error) = undo() {
+                println!("        * Unable to undo: {}", error);
+            }
+        }
+    }
+}
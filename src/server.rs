@@ -1,7 +1,11 @@
 //------------------------------------------------------------------------------
 // from+git_me@luketitley.com
 //------------------------------------------------------------------------------
+use crate::branch;
 use crate::config;
+use crate::error;
+use crate::error::Context as _;
+use crate::workspace;
 
 #[derive(
     Debug, PartialEq, serde::Serialize, serde::Deserialize, Default, Clone,
@@ -10,6 +14,7 @@ pub struct Project {
     pub id: u64,
     pub path_with_namespace: std::string::String,
     pub ssh_url_to_repo: std::string::String,
+    pub http_url_to_repo: std::string::String,
 }
 
 #[derive(
@@ -33,28 +38,34 @@ pub struct User {
     PartialEq,
     serde::Serialize,
     serde::Deserialize,
-    Default,
     Clone,
     Eq,
-    PartialOrd,
 )]
-pub struct Branch {
-    pub name: std::string::String,
-    pub commit: Commit,
+pub struct MergeRequest {
+    pub iid: u64,
+    pub web_url: std::string::String,
+    pub state: gitlab::types::MergeRequestState,
+    #[serde(default)]
+    pub title: std::string::String,
+    #[serde(default)]
+    pub source_branch: std::string::String,
+    #[serde(default)]
+    pub work_in_progress: bool,
+    #[serde(default)]
+    pub assignee: Option<User>,
+    #[serde(default)]
+    pub description: Option<std::string::String>,
+    #[serde(default)]
+    pub target_branch: std::string::String,
 }
 
 #[derive(
-    Debug,
-    PartialEq,
-    serde::Serialize,
-    serde::Deserialize,
-    Clone,
-    Eq,
+    Debug, PartialEq, serde::Serialize, serde::Deserialize, Default, Clone,
 )]
-pub struct MergeRequest {
+pub struct Issue {
     pub iid: u64,
+    pub title: std::string::String,
     pub web_url: std::string::String,
-    pub state: gitlab::types::MergeRequestState,
 }
 
 impl Default for MergeRequest {
@@ -63,6 +74,12 @@ impl Default for MergeRequest {
             iid: 0_u64,
             web_url: "".to_string(),
             state: gitlab::types::MergeRequestState::Merged,
+            title: "".to_string(),
+            source_branch: "".to_string(),
+            work_in_progress: false,
+            assignee: None,
+            description: None,
+            target_branch: "".to_string(),
         }
     }
 }
@@ -73,18 +90,30 @@ impl PartialOrd for MergeRequest {
     }
 }
 
-#[derive(
-    Debug,
-    PartialEq,
-    serde::Serialize,
-    serde::Deserialize,
-    Default,
-    Clone,
-    Eq,
-    PartialOrd,
-)]
-pub struct Commit {
-    pub id: std::string::String,
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize, Default)]
+pub struct Approvals {
+    pub approved: bool,
+}
+
+//------------------------------------------------------------------------------
+// The gitlab crate doesn't provide the approvals endpoint, so we add our own
+struct MergeRequestApprovals {
+    project: u64,
+    merge_request: u64,
+}
+
+impl gitlab::api::Endpoint for MergeRequestApprovals {
+    fn method(&self) -> gitlab::api::endpoint_prelude::Method {
+        gitlab::api::endpoint_prelude::Method::GET
+    }
+
+    fn endpoint(&self) -> std::borrow::Cow<'static, str> {
+        format!(
+            "projects/{}/merge_requests/{}/approvals",
+            self.project, self.merge_request
+        )
+        .into()
+    }
 }
 
 impl std::cmp::Ord for User {
@@ -106,208 +135,470 @@ pub fn to_nick(name: &str) -> &str {
 }
 
 impl Server {
-    pub fn new() -> Self {
-        let config = config::Config::open();
-        Server {
+    pub fn new() -> error::Result<Self> {
+        let config = config::Config::open()?;
+        Ok(Server {
             server: gitlab::Gitlab::new(&config.server, &config.private_token)
-                .expect("Unable to connect to server"),
-        }
+                .server_error("Unable to connect to server")?,
+        })
     }
 
-    pub fn project(&self, url: &str) -> Project {
+    /// The gitlab project the workspace's remote points at.
+    pub fn project(
+        &self,
+        workspace: &workspace::Workspace,
+    ) -> error::Result<Project> {
+        let url = branch::find_remote(workspace)?;
         let pageable_endpoint = gitlab::api::projects::Projects::builder()
             .build()
-            .expect("Unable to list all the project in the gitlab server");
+            .server_error("Unable to list all the project in the gitlab server")?;
 
         use gitlab::api::Query as _;
         let projects: Vec<Project> =
             gitlab::api::paged(pageable_endpoint, gitlab::api::Pagination::All)
                 .query(&self.server)
-                .expect("List projects query failed");
+                .server_error("List projects query failed")?;
 
         for project in projects.iter() {
-            if &project.ssh_url_to_repo == url {
-                return project.clone();
+            if project.ssh_url_to_repo == url || project.http_url_to_repo == url
+            {
+                return Ok(project.clone());
             }
         }
 
-        println!("Unable to find gitlab project for current repo {}", url);
-        println!("Projects are:");
+        let mut hint = "Projects are:\n".to_string();
         for project in projects.iter() {
-            println!("    {}", &project.ssh_url_to_repo);
+            hint.push_str(&format!(
+                "    {} {}\n",
+                &project.ssh_url_to_repo, &project.http_url_to_repo
+            ));
         }
 
-        println!("It could be that your repo url is out of date. Try:");
-        println!("    git remote remove origin");
-        println!("    git remote add origin <<your new url goes here>>");
-
-        panic!("See error above");
+        let remote_name = branch::remote_name(workspace)?;
+        hint.push_str("It could be that your repo url is out of date. Try:\n");
+        hint.push_str(&format!("    git remote remove {}\n", remote_name));
+        hint.push_str(&format!(
+            "    git remote add {} <<your new url goes here>>",
+            remote_name
+        ));
+
+        Err(error::Error::server(&format!(
+            "Unable to find gitlab project for current repo {}",
+            url
+        ))
+        .hint(&hint))
     }
 
-    pub fn find_user(&mut self, mut username: &str, resolve_nick: bool) -> User {
+    pub fn find_user(
+        &self,
+        mut username: &str,
+        resolve_nick: bool,
+    ) -> error::Result<User> {
         if resolve_nick {
             username = from_nick(username);
         }
 
         let pageable_endpoint = gitlab::api::users::Users::builder()
             .build()
-            .expect("Unable to list all the users in the gitlab server");
+            .server_error("Unable to list all the users in the gitlab server")?;
 
         use gitlab::api::Query as _;
         let mut users: Vec<User> =
             gitlab::api::paged(pageable_endpoint, gitlab::api::Pagination::All)
                 .query(&self.server)
-                .expect("List users query failed");
+                .server_error("List users query failed")?;
 
         for user in users.iter() {
             if &user.username == username {
-                return user.clone();
+                return Ok(user.clone());
             }
         }
 
-        println!("Unable to find user '{}' users are:", username);
+        let mut hint = "Users are:".to_string();
         users.sort();
         for user in users.iter() {
             let nick = to_nick(&user.username);
-            println!("    {}", nick);
+            hint.push_str(&format!("\n    {}", nick));
         }
 
-        panic!("Unable to find user")
+        Err(error::Error::user(&format!(
+            "Unable to find user '{}'",
+            username
+        ))
+        .hint(&hint))
     }
 
-    pub fn find_head_commit(
-        &mut self,
-        url: &str,
-        name: &str,
-    ) -> std::string::String {
-        let project = self.project(url);
+    pub fn merge_request(
+        &self,
+        project: &Project,
+        base: &str,
+        branch: &str,
+        remove_source_branch: bool,
+        description: Option<&str>,
+    ) -> error::Result<()> {
+        let title = format!("WIP: {}", branch);
+        let mut builder =
+            gitlab::api::projects::merge_requests::CreateMergeRequest::builder(
+            );
+        builder
+            .project(project.id)
+            .source_branch(branch)
+            .remove_source_branch(remove_source_branch)
+            .target_branch(base)
+            .title(&title);
+        if let Some(description) = description {
+            builder.description(description);
+        }
+        let endpoint = builder
+            .build()
+            .server_error("Unable to list all the project in the gitlab server")?;
+
+        use gitlab::api::Query as _;
+        gitlab::api::ignore(endpoint)
+            .query(&self.server)
+            .server_error("Create merge request failed")
+    }
+
+    /// Open a copy of merge_request from another branch, as gitlab can't
+    /// change the branch a merge request is from.
+    pub fn recreate_merge_request(
+        &self,
+        project: &Project,
+        merge_request: &MergeRequest,
+        branch: &str,
+        remove_source_branch: bool,
+    ) -> error::Result<MergeRequest> {
+        let title = merge_request
+            .title
+            .replace(&merge_request.source_branch, branch);
+        let description = format!(
+            "{}\n\nCarries on from !{}",
+            merge_request.description.as_deref().unwrap_or("").trim_end(),
+            merge_request.iid
+        );
+        let mut builder =
+            gitlab::api::projects::merge_requests::CreateMergeRequest::builder(
+            );
+        builder
+            .project(project.id)
+            .source_branch(branch)
+            .remove_source_branch(remove_source_branch)
+            .target_branch(&merge_request.target_branch)
+            .title(&title)
+            .description(description.trim_start());
+        if let Some(assignee) = &merge_request.assignee {
+            builder.assignee(assignee.id);
+        }
+        let endpoint = builder
+            .build()
+            .server_error("Unable to build merge request")?;
+
+        use gitlab::api::Query as _;
+        endpoint
+            .query(&self.server)
+            .server_error("Create merge request failed")
+    }
+
+    /// Close a merge request, leaving a note to say why.
+    pub fn close_merge_request(
+        &self,
+        project: &Project,
+        merge_request: u64,
+        note: &str,
+    ) -> error::Result<()> {
+        use gitlab::api::projects::merge_requests::notes;
+        use gitlab::api::Query as _;
+        let endpoint = notes::CreateMergeRequestNote::builder()
+            .project(project.id)
+            .merge_request(merge_request)
+            .body(note)
+            .build()
+            .server_error("Unable to build merge request note")?;
+        gitlab::api::ignore(endpoint)
+            .query(&self.server)
+            .server_error("Add merge request note failed")?;
 
         let endpoint =
-            gitlab::api::projects::repository::branches::Branches::builder()
+            gitlab::api::projects::merge_requests::EditMergeRequest::builder()
                 .project(project.id)
+                .merge_request(merge_request)
+                .state_event(gitlab::api::projects::merge_requests::MergeRequestStateEvent::Close)
                 .build()
-                .expect("Unable to list all the branches in the given project");
+                .server_error("Unable to edit merge request")?;
+        gitlab::api::ignore(endpoint)
+            .query(&self.server)
+            .server_error("Close merge request failed")
+    }
 
+    /// Reopen a merge request that was closed.
+    pub fn reopen_merge_request(
+        &self,
+        project: &Project,
+        merge_request: u64,
+    ) -> error::Result<()> {
+        use gitlab::api::projects::merge_requests::MergeRequestStateEvent;
         use gitlab::api::Query as _;
-        let branches: Vec<Branch> = endpoint
+        let endpoint =
+            gitlab::api::projects::merge_requests::EditMergeRequest::builder()
+                .project(project.id)
+                .merge_request(merge_request)
+                .state_event(MergeRequestStateEvent::Reopen)
+                .build()
+                .server_error("Unable to edit merge request")?;
+        gitlab::api::ignore(endpoint)
             .query(&self.server)
-            .expect("List projects query failed");
-
-        for branch in branches.iter() {
-            if &branch.name == name {
-                return branch.commit.id.clone();
-            }
-        }
-
-        panic!("Given branch {} not found", name);
+            .server_error("Reopen merge request failed")
     }
 
-    pub fn merge_request(
-        &mut self,
+    pub fn back_merge_request(
+        &self,
         project: &Project,
-        base: &str,
+        target: &str,
         branch: &str,
-    ) {
-        let title = format!("WIP: {}", branch);
+    ) -> error::Result<std::string::String> {
+        let title = format!("Back-merge {} into {}", branch, target);
         let endpoint =
             gitlab::api::projects::merge_requests::CreateMergeRequest::builder(
             )
             .project(project.id)
             .source_branch(branch)
             .remove_source_branch(true)
-            .target_branch(base)
+            .target_branch(target)
             .title(&title)
             .build()
-            .expect("Unable to list all the project in the gitlab server");
+            .server_error("Unable to build back-merge request")?;
 
         use gitlab::api::Query as _;
-        gitlab::api::ignore(endpoint)
+        let merge_request: MergeRequest = endpoint
             .query(&self.server)
-            .expect("Create merge request failed");
+            .server_error("Create back-merge request failed")?;
+
+        Ok(merge_request.web_url)
     }
 
     pub fn find_merge_request(
         &self,
         project: &Project,
         branch: &str,
-    ) -> Option<(u64, std::string::String, gitlab::types::MergeRequestState)> {
+    ) -> error::Result<
+        Option<(u64, std::string::String, gitlab::types::MergeRequestState)>,
+    > {
+        self.find_merge_request_into(project, branch, None)
+    }
+
+    pub fn find_merge_request_into(
+        &self,
+        project: &Project,
+        branch: &str,
+        target: Option<&str>,
+    ) -> error::Result<
+        Option<(u64, std::string::String, gitlab::types::MergeRequestState)>,
+    > {
+        Ok(self
+            .find_merge_request_details(project, branch, target)?
+            .map(|mr| (mr.iid, mr.web_url, mr.state)))
+    }
+
+    pub fn find_issue(
+        &self,
+        project: &Project,
+        iid: u64,
+    ) -> error::Result<Issue> {
+        let endpoint = gitlab::api::projects::issues::Issue::builder()
+            .project(project.id)
+            .issue(iid)
+            .build()
+            .server_error("Unable to find issue")?;
+
+        use gitlab::api::Query as _;
+        endpoint.query(&self.server).server_error(&format!(
+            "Unable to find issue #{} in {}",
+            iid, project.path_with_namespace
+        ))
+    }
+
+    pub fn find_merge_request_details(
+        &self,
+        project: &Project,
+        branch: &str,
+        target: Option<&str>,
+    ) -> error::Result<Option<MergeRequest>> {
+        let mut builder =
+            gitlab::api::projects::merge_requests::MergeRequests::builder();
+        builder.project(project.id).source_branch(branch);
+        if let Some(target) = target {
+            builder.target_branch(target);
+        }
+        let endpoint =
+            builder.build().server_error("Unable to find merge request")?;
+
+        use gitlab::api::Query as _;
+        let mrs: Vec<MergeRequest> = endpoint
+            .query(&self.server)
+            .server_error("List merge request query failed")?;
+
+        Ok(mrs.into_iter().next())
+    }
+
+    pub fn find_merge_request_by_iid(
+        &self,
+        project: &Project,
+        iid: u64,
+    ) -> error::Result<MergeRequest> {
+        let endpoint =
+            gitlab::api::projects::merge_requests::MergeRequest::builder()
+                .project(project.id)
+                .merge_request(iid)
+                .build()
+                .server_error("Unable to find merge request")?;
+
+        use gitlab::api::Query as _;
+        endpoint
+            .query(&self.server)
+            .server_error(&format!("Unable to find merge request !{}", iid))
+    }
+
+    pub fn find_open_merge_requests_into(
+        &self,
+        project: &Project,
+        target: &str,
+    ) -> error::Result<std::vec::Vec<MergeRequest>> {
         let endpoint =
             gitlab::api::projects::merge_requests::MergeRequests::builder()
                 .project(project.id)
-                .source_branch(branch)
+                .target_branch(target)
+                .state(gitlab::api::projects::merge_requests::MergeRequestState::Opened)
                 .build()
-                .expect("Unable to find merge request");
+                .server_error("Unable to find merge requests")?;
 
         use gitlab::api::Query as _;
-        let mrs: Vec<MergeRequest> = endpoint
+        endpoint
             .query(&self.server)
-            .expect("List merge request query failed");
+            .server_error("List merge request query failed")
+    }
 
-        if !mrs.is_empty() {
-            Some((mrs[0].iid, mrs[0].web_url.clone(), mrs[0].state))
-        } else {
-            None
-        }
+    pub fn retarget_merge_request(
+        &self,
+        project: &Project,
+        merge_request: u64,
+        target: &str,
+    ) -> error::Result<()> {
+        let endpoint =
+            gitlab::api::projects::merge_requests::EditMergeRequest::builder()
+                .project(project.id)
+                .merge_request(merge_request)
+                .target_branch(target)
+                .build()
+                .server_error("Unable to edit merge request")?;
+
+        use gitlab::api::Query as _;
+        gitlab::api::ignore(endpoint)
+            .query(&self.server)
+            .server_error("Retarget merge request failed")
+    }
+
+    pub fn approved(
+        &self,
+        project: &Project,
+        merge_request: u64,
+    ) -> error::Result<bool> {
+        let endpoint = MergeRequestApprovals {
+            project: project.id,
+            merge_request,
+        };
+
+        use gitlab::api::Query as _;
+        let approvals: Approvals = endpoint
+            .query(&self.server)
+            .server_error("Merge request approvals query failed")?;
+
+        Ok(approvals.approved)
+    }
+
+    pub fn accept_merge_request(
+        &self,
+        project: &Project,
+        merge_request: u64,
+        remove_source_branch: bool,
+    ) -> error::Result<()> {
+        let endpoint =
+            gitlab::api::projects::merge_requests::MergeMergeRequest::builder()
+                .project(project.id)
+                .merge_request(merge_request)
+                .should_remove_source_branch(remove_source_branch)
+                .build()
+                .server_error("Unable to build accept merge request")?;
+
+        use gitlab::api::Query as _;
+        gitlab::api::ignore(endpoint)
+            .query(&self.server)
+            .server_error("Accept merge request failed")
+            .map_err(|e| {
+                e.hint("Is it still WIP, or has the pipeline failed?")
+            })
     }
 
-    pub fn list_projects<F>(&self, mut f: F)
+    pub fn list_projects<F>(&self, mut f: F) -> error::Result<()>
     where
         F: FnMut(&Project),
     {
         let pageable_endpoint = gitlab::api::projects::Projects::builder()
             .build()
-            .expect("Unable to list all the project in the gitlab server");
+            .server_error("Unable to list all the project in the gitlab server")?;
 
         use gitlab::api::Query as _;
         let projects: Vec<Project> =
             gitlab::api::paged(pageable_endpoint, gitlab::api::Pagination::All)
                 .query(&self.server)
-                .expect("List projects query failed");
+                .server_error("List projects query failed")?;
 
         for project in projects.iter() {
             f(project)
         }
+        Ok(())
     }
 
     pub fn final_merge_request(
         &self,
         project: &Project,
-        branch: &str,
+        merge_request_id: u64,
+        title: &str,
         description: &str,
-        reviewers: &[std::string::String],
         primary_reviewer: u64,
-        dev_name: &str,
-    ) -> [std::string::String; 2] {
-        let new_title = format!("{}", branch);
-        let (merge_request_id, merge_request_url, merge_request_state) = self
-            .find_merge_request(project, branch)
-            .expect("Unable to find merge request");
-
+    ) -> error::Result<()> {
         let endpoint =
             gitlab::api::projects::merge_requests::EditMergeRequest::builder()
                 .project(project.id)
                 .merge_request(merge_request_id)
-                .title(&new_title)
+                .title(title)
                 .assignee(primary_reviewer)
                 .description(description)
                 .state_event(gitlab::api::projects::merge_requests::MergeRequestStateEvent::Reopen)
                 .build()
-                .expect("Unable to edit merge request");
+                .server_error("Unable to edit merge request")?;
 
         use gitlab::api::Query as _;
         gitlab::api::ignore(endpoint)
             .query(&self.server)
-            .expect("Edit merge request failed");
+            .server_error("Edit merge request failed")
+    }
+}
 
-        let mut mention = std::string::String::new();
-        for r in reviewers.iter() {
-            mention.push_str(&format!("<at>{}</at>\n\n", r));
-        }
+//------------------------------------------------------------------------------
+/// The message announcing a merge request is ready for review.
+pub fn merge_request_summary(
+    merge_request_url: &str,
+    description: &str,
+    reviewers: &[std::string::String],
+    dev_name: &str,
+) -> std::string::String {
+    let mut mention = std::string::String::new();
+    for r in reviewers.iter() {
+        mention.push_str(&format!("<at>{}</at>\n\n", r));
+    }
 
-        [
-            branch.to_string(),
-            format!(
-                r#"
+    format!(
+        r#"
 {}  
 
 {}  
@@ -318,8 +609,6 @@ _{}_
 
 {}
 "#,
-                mention, merge_request_url, dev_name, description
-            ),
-        ]
-    }
+        mention, merge_request_url, dev_name, description
+    )
 }
@@ -3,39 +3,78 @@
 //------------------------------------------------------------------------------
 use crate::branch;
 use crate::changelog;
+use crate::error;
+use crate::plan;
+use crate::workflow;
+use crate::workspace;
 
 //------------------------------------------------------------------------------
-pub fn aggregate(tag: &str) {
+pub fn aggregate(
+    workspace: &workspace::Workspace,
+    tag: &str,
+    dry_run: bool,
+) -> error::Result<()> {
     // Build the aggregate changelog
-    let _ = changelog::aggregate(tag, &["feature", "hotfix"]);
+    let prefixes = workflow::Workflow::open(workspace)?.changelog_prefixes();
+    let mut plan = plan::Plan::new();
+    {
+        let workspace = workspace.clone();
+        let tag = tag.to_string();
+        plan.add(&format!("Aggregate the changelogs for {}", tag), move || {
+            changelog::aggregate(&workspace, &tag, &prefixes)?;
+            Ok(())
+        });
+    }
+    plan.run(dry_run)?;
+    Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn validate(path: &str) {
+pub fn validate(path: &str) -> error::Result<()> {
     // Build the aggregate changelog
-    if !changelog::validate(&std::path::PathBuf::from(path)) {
-        panic!("Failed to validate {}", path);
+    if !changelog::validate(&std::path::PathBuf::from(path))? {
+        return Err(error::Error::changelog(&format!(
+            "Failed to validate {}, it's empty",
+            path
+        )));
     }
+    Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn edit(commit: bool, from_last_commit: bool) {
-    let branch_name = branch::find_name();
+pub fn edit(
+    workspace: &workspace::Workspace,
+    commit: bool,
+    from_last_commit: bool,
+    dry_run: bool,
+) -> error::Result<()> {
+    let branch_name = branch::find_name(workspace)?;
 
     let msg = if from_last_commit {
-        let last_commit_msg = branch::find_last_commit_msg()
+        let last_commit_msg = branch::find_last_commit_msg(workspace)?
                                             .replace('\n', "");
         Some(last_commit_msg)
     } else {
         None
     };
 
-    if ! (branch_name.starts_with("hotfix") ||
-            branch_name.starts_with("feature")) {
-        panic!("You are on the {} branch. You can only edit changelogs on feature or hotfix branches", branch_name);
+    match branch::find_type(workspace)? {
+        Some(branch_type) if !branch_type.release => (),
+        _ => return Err(error::Error::user(&format!("You are on the {} branch. You can only edit changelogs on feature or hotfix branches", branch_name))),
     }
 
-    let repo_path = branch::find_path();
-
-    changelog::edit(&branch_name, Some(&repo_path), commit, msg);
+    let description = if commit {
+        format!("Edit and commit the changelog for {}", branch_name)
+    } else {
+        format!("Edit the changelog for {}", branch_name)
+    };
+    let mut plan = plan::Plan::new();
+    {
+        let workspace = workspace.clone();
+        plan.add(&description, move || {
+            changelog::edit(&workspace, &branch_name, commit, msg)
+        });
+    }
+    plan.run(dry_run)?;
+    Ok(())
 }
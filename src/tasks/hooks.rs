@@ -0,0 +1,316 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::branch;
+use crate::changelog;
+use crate::error;
+use crate::error::Context as _;
+use crate::plan;
+use crate::workflow;
+use crate::workspace;
+use std::os::unix::fs::PermissionsExt;
+
+const HOOKS: [&str; 3] = ["pre-push", "commit-msg", "post-checkout"];
+
+/// How we know a hook is one of ours.
+const MARKER: &str = "# Installed by git-me";
+
+/// Hooks that were there before ours are moved here, and still run.
+const CHAINED: &str = ".pre-git-me";
+
+//------------------------------------------------------------------------------
+/// Install the hooks, which call back into 'git me hooks run'.
+pub fn install(
+    workspace: &workspace::Workspace,
+    dry_run: bool,
+) -> error::Result<()> {
+    let hooks = branch::hooks_path(workspace)?;
+    let mut plan = plan::Plan::new();
+    if !hooks.exists() {
+        let hooks = hooks.clone();
+        plan.add(&format!("Create {}", hooks.display()), move || {
+            std::fs::create_dir_all(&hooks)
+                .git_error("Unable to create the hooks folder")
+        });
+    }
+
+    for hook in HOOKS.iter() {
+        let path = hooks.join(hook);
+        let chained = chained_path(&path);
+
+        // Keep whatever hook was there, and run it first
+        if path.exists() && !installed(&path) {
+            if chained.exists() {
+                return Err(error::Error::user(&format!(
+                    "There's already a hook chained at {}",
+                    chained.display()
+                ))
+                .hint("Move one of them out of the way"));
+            }
+            let path = path.clone();
+            let undo_path = path.clone();
+            let undo_chained = chained.clone();
+            plan.add_with_undo(
+                &format!("Chain the {} hook that's there", hook),
+                move || move_hook(&path, &chained),
+                move || move_hook(&undo_chained, &undo_path),
+            );
+        }
+
+        let undo_path = path.clone();
+        plan.add_with_undo(
+            &format!("Install {}", path.display()),
+            move || write_hook(&path, hook),
+            move || {
+                std::fs::remove_file(&undo_path).git_error(&format!(
+                    "Unable to remove {}",
+                    undo_path.display()
+                ))
+            },
+        );
+    }
+
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Remove our hooks, and put back any they were chained to.
+pub fn uninstall(
+    workspace: &workspace::Workspace,
+    dry_run: bool,
+) -> error::Result<()> {
+    let hooks = branch::hooks_path(workspace)?;
+    let mut plan = plan::Plan::new();
+
+    for hook in HOOKS.iter() {
+        let path = hooks.join(hook);
+        if !path.exists() {
+            continue;
+        }
+        if !installed(&path) {
+            println!("    * Leave {}, it isn't one of ours", path.display());
+            continue;
+        }
+
+        {
+            let path = path.clone();
+            let undo_path = path.clone();
+            plan.add_with_undo(
+                &format!("Remove {}", path.display()),
+                move || {
+                    std::fs::remove_file(&path).git_error(&format!(
+                        "Unable to remove {}",
+                        path.display()
+                    ))
+                },
+                move || write_hook(&undo_path, hook),
+            );
+        }
+
+        let chained = chained_path(&path);
+        if chained.exists() {
+            let undo_path = path.clone();
+            let undo_chained = chained.clone();
+            plan.add_with_undo(
+                &format!("Put back the {} hook it was chained to", hook),
+                move || move_hook(&chained, &path),
+                move || move_hook(&undo_path, &undo_chained),
+            );
+        }
+    }
+
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Called by the hooks, with the arguments git gave them.
+pub fn run(
+    workspace: &workspace::Workspace,
+    hook: &str,
+    args: &[std::string::String],
+) -> error::Result<()> {
+    match hook {
+        "pre-push" => pre_push(workspace),
+        "commit-msg" => match args.first() {
+            Some(path) => commit_msg(workspace, std::path::Path::new(path)),
+            None => {
+                Err(error::Error::user("commit-msg needs the message file"))
+            }
+        },
+        "post-checkout" => post_checkout(workspace, args),
+        _ => Err(error::Error::user(&format!("There's no {} hook", hook))
+            .hint(&format!("Hooks are {}", HOOKS.join(", ")))),
+    }
+}
+
+//------------------------------------------------------------------------------
+/// Check the changelog before it goes anywhere. Only the current branch's is
+/// on disk to check, which is usually the one being pushed.
+fn pre_push(workspace: &workspace::Workspace) -> error::Result<()> {
+    let current = match branch::find_name(workspace) {
+        Ok(current) => current,
+        Err(_) => return Ok(()),
+    };
+    let branch_type = match branch::find_type(workspace)? {
+        Some(branch_type) if !branch_type.release => branch_type,
+        _ => return Ok(()),
+    };
+
+    // Git gives us '<local ref> <local sha> <remote ref> <remote sha>' lines
+    let mut input = std::string::String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+        .git_error("Unable to read the refs being pushed")?;
+    let pushing = input.lines().any(|line| {
+        let fields: std::vec::Vec<&str> = line.split_whitespace().collect();
+        fields.len() == 4
+            && fields[0] == format!("refs/heads/{}", current)
+            && fields[1].chars().any(|c| c != '0')
+    });
+    if !pushing {
+        return Ok(());
+    }
+
+    let changelog_name = branch::changelog_name(&branch_type, &current);
+    let path = changelog::resolve(workspace, &changelog_name);
+    if !path.exists() {
+        return Err(error::Error::user(&format!(
+            "{} has no changelog",
+            current
+        ))
+        .hint("Make one with 'git changelog edit', or push with --no-verify"));
+    }
+    if !changelog::validate(&path)? {
+        return Err(error::Error::changelog(&format!(
+            "The changelog for {} isn't valid",
+            current
+        ))
+        .hint("Fix it with 'git changelog edit', or push with --no-verify"));
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+fn commit_msg(
+    workspace: &workspace::Workspace,
+    path: &std::path::Path,
+) -> error::Result<()> {
+    let message = std::fs::read_to_string(path)
+        .git_error("Unable to read the commit message")?;
+
+    // Git strips the comments after the hook has run
+    let message = message
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<std::vec::Vec<_>>()
+        .join("\n");
+
+    // The commits git-me makes don't have to follow the rules
+    let workflow = workflow::Workflow::open(workspace)?;
+    if let Ok(branch_name) = branch::find_name(workspace) {
+        if let Some(branch_type) = workflow.find_by_branch(&branch_name) {
+            let summary = message.lines().next().unwrap_or("");
+            if branch::made_by_git_me(&branch_type, &branch_name, summary) {
+                return Ok(());
+            }
+        }
+    }
+
+    let problems = workflow.commits.check(&message, false)?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    for problem in problems.iter() {
+        println!("    * {}", problem);
+    }
+    Err(
+        error::Error::user("Your commit message doesn't follow the rules")
+            .hint("Fix it and commit again, or commit with --no-verify"),
+    )
+}
+
+//------------------------------------------------------------------------------
+/// Warn about branches without a changelog, so it isn't left to review.
+fn post_checkout(
+    workspace: &workspace::Workspace,
+    args: &[std::string::String],
+) -> error::Result<()> {
+    // Git passes 1 when a branch was checked out, rather than files
+    if args.get(2).map(std::string::String::as_str) != Some("1") {
+        return Ok(());
+    }
+
+    let branch_name = match branch::find_name(workspace) {
+        Ok(branch_name) => branch_name,
+        Err(_) => return Ok(()),
+    };
+    if let Some(branch_type) = branch::find_type(workspace)? {
+        let changelog_name = branch::changelog_name(&branch_type, &branch_name);
+        if !branch_type.release
+            && !changelog::resolve(workspace, &changelog_name).exists()
+        {
+            println!("git-me: {} has no changelog", branch_name);
+            println!("    Make one with 'git changelog edit'");
+        }
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+fn write_hook(path: &std::path::Path, hook: &str) -> error::Result<()> {
+    std::fs::write(path, script(hook))
+        .git_error(&format!("Unable to write {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+        .git_error(&format!("Unable to make {} executable", path.display()))
+}
+
+//------------------------------------------------------------------------------
+fn move_hook(
+    from: &std::path::Path,
+    to: &std::path::Path,
+) -> error::Result<()> {
+    std::fs::rename(from, to)
+        .git_error(&format!("Unable to move {}", from.display()))
+}
+
+//------------------------------------------------------------------------------
+fn installed(path: &std::path::Path) -> bool {
+    std::fs::read_to_string(path)
+        .map(|hook| hook.contains(MARKER))
+        .unwrap_or(false)
+}
+
+//------------------------------------------------------------------------------
+fn chained_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut chained = path.as_os_str().to_owned();
+    chained.push(CHAINED);
+    std::path::PathBuf::from(chained)
+}
+
+//------------------------------------------------------------------------------
+/// The hook runs the one it's chained to first, and stops if that fails.
+/// Pre-push reads the refs from stdin, so they're kept to hand to both.
+fn script(hook: &str) -> std::string::String {
+    let (read, pipe) = if hook == "pre-push" {
+        ("input=$(cat)\n", "printf '%s\\n' \"$input\" | ")
+    } else {
+        ("", "")
+    };
+
+    format!(
+        r#"#!/bin/sh
+{marker}, remove with 'git me hooks uninstall'
+{read}if [ -x "$0{chained}" ]; then
+    {pipe}"$0{chained}" "$@" || exit $?
+fi
+{pipe}git me hooks run {hook} "$@"
+"#,
+        marker = MARKER,
+        read = read,
+        chained = CHAINED,
+        pipe = pipe,
+        hook = hook
+    )
+}
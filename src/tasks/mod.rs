@@ -3,5 +3,24 @@
 //------------------------------------------------------------------------------
 
 pub mod changelog;
+pub mod hooks;
 pub mod setup;
+pub mod status;
+pub mod sync;
 pub mod work;
+pub mod worktree;
+
+use crate::error;
+
+//------------------------------------------------------------------------------
+fn confirm(question: &str) -> error::Result<bool> {
+    use std::io::Write as _;
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().unwrap_or(());
+
+    let mut answer = std::string::String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .map_err(|_| error::Error::user("Unable to read your answer"))?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
@@ -2,21 +2,24 @@
 // from+git_me@luketitley.com
 //------------------------------------------------------------------------------
 use crate::config;
+use crate::error;
+use crate::error::Context as _;
 
-pub fn setup(server: &str, token: &str) {
-    let _ = gitlab::Gitlab::new(server, token)
-        .expect("Unable to connect to server, to verify server and token.");
+pub fn setup(server: &str, token: &str) -> error::Result<()> {
+    let _ = gitlab::Gitlab::new(server, token).server_error(
+        "Unable to connect to server, to verify server and token",
+    )?;
 
     config::Config {
         server: server.to_string(),
         private_token: token.to_string(),
     }
-    .save();
+    .save()
 }
 
-pub fn info() {
-    println!("{}", config::Config::file_path());
-    let config = config::Config::open();
+pub fn info() -> error::Result<()> {
+    println!("{}", config::Config::file_path()?);
+    let config = config::Config::open()?;
     let server_status =
         match gitlab::Gitlab::new(&config.server, &config.private_token) {
             Ok(_) => "Contactable".to_string(),
@@ -25,4 +28,5 @@ pub fn info() {
 
     println!("    {:?}", &config);
     println!("Server: {}", server_status);
+    Ok(())
 }
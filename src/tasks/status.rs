@@ -0,0 +1,97 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::branch;
+use crate::changelog;
+use crate::error;
+use crate::server;
+use crate::workspace;
+
+//------------------------------------------------------------------------------
+pub fn status(workspace: &workspace::Workspace) -> error::Result<()> {
+    let branch_name = branch::find_name(workspace)?;
+
+    let branch_type = if let Some(branch_type) = branch::find_type(workspace)? {
+        branch_type
+    } else {
+        println!("    * Branch     {} (not a workflow branch)", branch_name);
+        return Ok(());
+    };
+
+    println!("    * Branch     {} ({})", branch_name, branch_type.name);
+    let base = branch::base_of(workspace, &branch_type, &branch_name)?;
+    let target = branch::target_of(workspace, &branch_type, &branch_name)?;
+    println!("    * Base       {}, merges into {}", base, target);
+
+    // How far we've drifted from the base
+    let ancestry = branch::ancestry(workspace, &branch_name, &base)?;
+    println!(
+        "    * Remote     {} ahead, {} behind {}",
+        ancestry.ahead,
+        ancestry.behind,
+        branch::remote_branch(workspace, &base)?
+    );
+    if let Some(merge_base) = ancestry.merge_base {
+        println!("        forked from {}", merge_base);
+    }
+
+    // Is the changelog ready for review
+    let changelog_path = changelog::resolve(
+        workspace,
+        &branch::changelog_name(&branch_type, &branch_name),
+    );
+    let changelog_status = if !changelog_path.exists() {
+        "missing".to_string()
+    } else {
+        match changelog::validate(&changelog_path) {
+            Ok(true) => "ok".to_string(),
+            Ok(false) => "not filled in".to_string(),
+            Err(problem) => problem.to_string(),
+        }
+    };
+    println!(
+        "    * Changelog  {} {}",
+        changelog_path.display(),
+        changelog_status
+    );
+
+    // Local changes
+    println!("    * Tree");
+    if branch::verify_index_empty(workspace)? {
+        println!("        clean");
+    }
+
+    // The merge request
+    let server = server::Server::new()?;
+    let project = server.project(workspace)?;
+    match server.find_merge_request_details(
+        &project,
+        &branch_name,
+        Some(&target),
+    )? {
+        Some(merge_request) => {
+            println!(
+                "    * MR         !{} {}",
+                merge_request.iid, merge_request.web_url
+            );
+            println!(
+                "        {}, {:?}",
+                if merge_request.work_in_progress {
+                    "draft"
+                } else {
+                    "ready"
+                },
+                merge_request.state
+            );
+            match merge_request.assignee {
+                Some(assignee) => println!(
+                    "        assigned to {} ({})",
+                    assignee.name, assignee.username
+                ),
+                None => println!("        unassigned"),
+            }
+        }
+        None => println!("    * MR         none"),
+    }
+    Ok(())
+}
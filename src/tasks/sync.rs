@@ -0,0 +1,129 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::branch;
+use crate::error;
+use crate::plan;
+use crate::server;
+use crate::workflow;
+use crate::workspace;
+
+//------------------------------------------------------------------------------
+/// Catch up with the remote, and tidy away the branches that have landed.
+pub fn sync(
+    workspace: &workspace::Workspace,
+    yes: bool,
+    dry_run: bool,
+) -> error::Result<()> {
+    let workflow = workflow::Workflow::open(workspace)?;
+
+    println!("    * Fetch {}", branch::remote_name(workspace)?);
+    branch::fetch_all(workspace)?;
+
+    let mut plan = plan::Plan::new();
+
+    // Bring the bases up to date, as long as nobody has committed to them
+    for base in bases(&workflow) {
+        if !branch::exists(workspace, &base)? {
+            continue;
+        }
+
+        // A base with local commits is left alone, the rest can carry on
+        match branch::verify_fast_forward(workspace, &base) {
+            Ok(true) => (),
+            Ok(false) => {
+                println!("    * {} is up to date", base);
+                continue;
+            }
+            Err(error::Error::User { .. }) => {
+                println!(
+                    "    * {} has commits that aren't on the remote",
+                    base
+                );
+                println!("        leave it, and get them into a merge request");
+                continue;
+            }
+            Err(error) => return Err(error),
+        }
+
+        let workspace = workspace.clone();
+        plan.add(&format!("Fast forward {}", base), move || {
+            branch::fast_forward(&workspace, &base)
+        });
+    }
+
+    // Find the local branches whose merge requests are done with
+    println!("    * Check merge requests");
+    let server = server::Server::new()?;
+    let project = server.project(workspace)?;
+    let current = branch::find_name(workspace)?;
+    let checked_out: std::vec::Vec<std::string::String> =
+        branch::worktrees(workspace)?
+            .into_iter()
+            .filter_map(|w| w.branch)
+            .collect();
+
+    let mut stale = std::collections::BTreeMap::new();
+    for type_ in workflow.types.values().filter(|t| !t.release) {
+        for listing in branch::list(workspace, &type_.prefix)? {
+            if !listing.local || stale.contains_key(&listing.name) {
+                continue;
+            }
+
+            use gitlab::types::MergeRequestState;
+            match server.find_merge_request_details(
+                &project,
+                &listing.name,
+                None,
+            )? {
+                Some(mr)
+                    if mr.state == MergeRequestState::Merged
+                        || mr.state == MergeRequestState::Closed =>
+                {
+                    stale.insert(listing.name, mr);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let mut to_delete = std::vec::Vec::new();
+    for (name, mr) in stale.iter() {
+        println!("        {:<40} !{} {:?}", name, mr.iid, mr.state);
+        if name == &current || checked_out.contains(name) {
+            println!("            checked out, switch away to remove it");
+        } else {
+            to_delete.push(name.clone());
+        }
+    }
+
+    if !to_delete.is_empty()
+        && (yes || dry_run || super::confirm("Delete these branches?")?)
+    {
+        for name in to_delete {
+            let workspace = workspace.clone();
+            plan.add(&format!("Delete {}", name), move || {
+                branch::delete(&workspace, &name)
+            });
+        }
+    }
+
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// The long lived branches everything else forks from and lands in.
+fn bases(
+    workflow: &workflow::Workflow,
+) -> std::collections::BTreeSet<std::string::String> {
+    let mut bases = std::collections::BTreeSet::new();
+    for type_ in workflow.types.values() {
+        bases.insert(branch::base(type_).to_string());
+        bases.insert(branch::target(type_).to_string());
+        if let Some(back_merge) = branch::back_merge(type_) {
+            bases.insert(back_merge.to_string());
+        }
+    }
+    bases
+}
@@ -3,82 +3,325 @@
 //------------------------------------------------------------------------------
 use crate::branch;
 use crate::changelog;
+use crate::error;
+use crate::error::Context as _;
+use crate::history;
+use crate::plan;
 use crate::server;
 use crate::teams;
+use crate::workflow;
+use crate::workspace;
 
 //------------------------------------------------------------------------------
-pub fn start(branch_type: branch::Type, name: &str) {
-    // Verify there's nothing in the index
-    println!("    * Check nothing to commit");
-    if !branch::verify_index_empty() {
-        panic!("You have uncommited changes, please stash them.");
+#[allow(clippy::too_many_arguments)]
+pub fn start(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    name: Option<&str>,
+    issue: Option<u64>,
+    on: Option<&str>,
+    autostash: bool,
+    worktree: bool,
+    dry_run: bool,
+) -> error::Result<()> {
+    if name.is_none() && issue.is_none() {
+        return Err(error::Error::user("Your branch needs a name")
+            .hint("Give it one with -n, or use --issue to name it after one"));
     }
 
+    // Verify there's nothing in the index, a worktree leaves this checkout
+    // alone so it doesn't matter there
+    let stashed = !worktree && check_nothing_to_commit(workspace, autostash)?;
+
+    let server = std::rc::Rc::new(server::Server::new()?);
+    let project = server.project(workspace)?;
+
+    // Work from the issue, named after its title unless told otherwise
+    let issue = match issue {
+        Some(iid) => {
+            println!("    * Find issue #{}", iid);
+            if branch_type.changelog.is_some() {
+                println!(
+                    "        * The changelog template is used, not its title"
+                );
+            }
+            Some(server.find_issue(&project, iid)?)
+        }
+        None => None,
+    };
+    let name = match (name, &issue) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(issue)) => {
+            branch::name_from(&format!("{} {}", issue.iid, issue.title))
+        }
+        (None, None) => unreachable!(),
+    };
+    let name = name.as_str();
+
     // Verify the branch name has valid chars in it
     println!("    * Check name '{}' is well formed", name);
-    if !branch::well_formed(name) {
-        panic!("Your branch name has invalid characters in it '{}'.", name);
+    if !branch::well_formed_for(branch_type, name)? {
+        return Err(error::Error::user(&format!(
+            "Your branch name has invalid characters in it '{}'",
+            name
+        ))
+        .hint(&format!("It needs to match {}", branch_type.naming)));
     }
 
-    // Find the user specified in reviewer
-    let mut server = server::Server::new();
+    let branch_name = branch::resolve(branch_type, name);
+    let worktree_path = if worktree {
+        Some(branch::worktree_path(workspace, name)?)
+    } else {
+        None
+    };
+
+    let mut plan = plan::Plan::new();
+    if stashed {
+        stash(&mut plan, workspace);
+    }
 
     // Make the new branch
-    println!("    * {}", &branch::resolve(branch_type, name));
-    branch::branch(branch_type, name);
+    let description = match &worktree_path {
+        Some(path) => format!(
+            "Create {} from {} in {}",
+            branch_name,
+            on.unwrap_or_else(|| branch::base(branch_type)),
+            path.display()
+        ),
+        None => format!(
+            "Create {} from {}",
+            branch_name,
+            on.unwrap_or_else(|| branch::base(branch_type))
+        ),
+    };
+    {
+        let branch_type = branch_type.clone();
+        let name = name.to_string();
+        let on = on.map(str::to_string);
+        let worktree_path = worktree_path.clone();
+        let branch_name = branch_name.clone();
+        let undo_worktree_path = worktree_path.clone();
+        let undo_branch_name = branch_name.clone();
+        let changelog_msg = issue.as_ref().map(|i| i.title.clone());
+        let previous = branch::find_name(workspace)?;
+        let undo_workspace = workspace.clone();
+        let workspace = workspace.clone();
+        plan.add_with_undo(
+            &description,
+            move || {
+                branch::branch(
+                    &workspace,
+                    &branch_type,
+                    &name,
+                    on.as_deref(),
+                    worktree_path.as_deref(),
+                    changelog_msg.as_deref(),
+                )?;
+                branch::stack_on(&workspace, &branch_name, on.as_deref())
+            },
+            move || {
+                branch::unbranch(
+                    &undo_workspace,
+                    &undo_branch_name,
+                    &previous,
+                    undo_worktree_path.as_deref(),
+                )
+            },
+        );
+    }
+
+    // Bring the work in progress over to the new branch
+    if stashed {
+        restore_stash(&mut plan, workspace);
+    }
 
     // Push the new branch
-    println!("    * push");
-    branch::push(&branch::resolve(branch_type, name));
+    {
+        let branch_name = branch_name.clone();
+        let undo_branch_name = branch_name.clone();
+        let undo_workspace = workspace.clone();
+        let workspace = workspace.clone();
+        plan.add_with_undo(
+            &format!("Push {}", branch_name),
+            move || branch::push(&workspace, &branch_name),
+            move || branch::delete_remote(&undo_workspace, &undo_branch_name),
+        );
+    }
 
     // Set upstream
-    println!("    * set upstream");
-    branch::set_upstream(&branch::resolve(branch_type, name));
+    {
+        let branch_name = branch_name.clone();
+        let workspace = workspace.clone();
+        plan.add(
+            &format!(
+                "Set upstream to {}",
+                branch::remote_branch(&workspace, &branch_name)?
+            ),
+            move || branch::set_upstream(&workspace, &branch_name),
+        );
+    }
 
     // Create a new merge request upfront
-    println!("    * wip merge request");
-    let remote_url = branch::find_remote();
-    let project = server.project(&remote_url);
-    server.merge_request(
-        &project,
-        branch::base(branch_type),
-        &branch::resolve(branch_type, name),
-    );
+    {
+        let target = on
+            .unwrap_or_else(|| branch::target(branch_type))
+            .to_string();
+        // Keep the branch around if it still needs merging back
+        let remove_source_branch =
+            on.is_some() || branch::back_merge(branch_type).is_none();
+        let description = issue.map(|i| format!("Closes #{}", i.iid));
+        plan.add(
+            &format!(
+                "Create WIP merge request {} into {}",
+                branch_name, target
+            ),
+            move || {
+                server.merge_request(
+                    &project,
+                    &target,
+                    &branch_name,
+                    remove_source_branch,
+                    description.as_deref(),
+                )
+            },
+        );
+    }
+
+    if plan.run(dry_run)? {
+        if let Some(worktree_path) = worktree_path {
+            println!("Your worktree is ready:");
+            println!("    cd {}", worktree_path.display());
+        }
+    }
+    Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn review(finished: std::vec::Vec<std::string::String>) {
-    let branch_type = if let Some(branch_type) = branch::find_type() {
-        branch_type
-    } else {
-        panic!("Unable to determine branch type, must be hotfix or feature");
-    };
-
-    let branch_name = branch::find_name();
-    let repo_path = branch::find_path();
+pub fn review(
+    workspace: &workspace::Workspace,
+    finished: std::vec::Vec<std::string::String>,
+    autostash: bool,
+    autosquash: bool,
+    squash_changelog: bool,
+    override_rules: bool,
+    dry_run: bool,
+) -> error::Result<()> {
+    let branch_type = current_type(workspace)?;
+    let branch_name = branch::find_name(workspace)?;
 
     // Verify there's nothing in the index
-    println!("    * Check outstanding changes");
-    if !branch::verify_index_empty() {
-        panic!("You have uncommited changes");
-    }
+    let stashed = check_nothing_to_commit(workspace, autostash)?;
 
     // Verify that our branch is up to speed
     println!("    * Check rebased");
-    let mut server = server::Server::new();
-    let remote_url = branch::find_remote();
-    let head_commit =
-        server.find_head_commit(&remote_url, branch::base(branch_type));
+    let server = std::rc::Rc::new(server::Server::new()?);
+    let project = server.project(workspace)?;
+    let base = branch::base_of(workspace, &branch_type, &branch_name)?;
+    let ancestry = branch::ancestry(workspace, &branch_name, &base)?;
+    if !ancestry.base_reachable {
+        println!("        * {} behind {}", ancestry.behind, base);
+    }
+
+    // Check the commit messages, which have to follow the rules before the
+    // final review
+    println!("    * Check commit messages");
+    let final_review = !finished.is_empty();
+    let violations = branch::lint(
+        workspace,
+        &branch_type,
+        &branch_name,
+        &base,
+        &workflow::Workflow::open(workspace)?.commits,
+        final_review,
+        autosquash,
+    )?;
+    for violation in violations.iter() {
+        println!(
+            "        * {} {}",
+            &violation.commit.to_string()[..8],
+            violation.summary
+        );
+        for problem in violation.problems.iter() {
+            println!("            {}", problem);
+        }
+    }
+    if final_review && !violations.is_empty() && !override_rules {
+        return Err(error::Error::user(&format!(
+            "{} commits don't follow the commit message rules",
+            violations.len()
+        ))
+        .hint("Reword them with 'git rebase -i', or use --override-rules"));
+    }
+
+    let mut plan = plan::Plan::new();
+    if stashed {
+        stash(&mut plan, workspace);
+    }
 
     // Verify that your branch is rebased on top of the latest work in base
-    if !branch::verify_up_to_date(&head_commit, &branch_name) {
-        println!("        * Rebasing");
-        branch::rebase_place_holder(branch_type, &branch_name);
+    if !ancestry.base_reachable {
+        let workspace = workspace.clone();
+        let branch_type = branch_type.clone();
+        let branch_name = branch_name.clone();
+        let base = base.clone();
+        plan.add_step(
+            &format!("Rebase {} onto {}", branch_name, base),
+            move || match branch::rebase(&workspace, &base, &branch_name)? {
+                branch::RebaseOutcome::Finished => Ok(plan::Outcome::Continue),
+                branch::RebaseOutcome::Conflicts(paths) => {
+                    report_conflicts(&branch_type, &paths);
+                    if stashed {
+                        println!("Your changes are stashed, 'git stash pop' them once the rebase is done.");
+                    }
+                    Ok(plan::Outcome::Stop)
+                }
+            },
+        );
+    }
+
+    // Tidy up the history for the reviewers, which has to be force pushed
+    let tidy = autosquash || squash_changelog;
+    if tidy {
+        let workspace = workspace.clone();
+        let branch_type = branch_type.clone();
+        let branch_name = branch_name.clone();
+        let description = if squash_changelog {
+            "Fold fixups and changelog updates"
+        } else {
+            "Fold fixups"
+        };
+        plan.add(description, move || {
+            if !branch::tidy(
+                &workspace,
+                &branch_type,
+                &branch_name,
+                &base,
+                squash_changelog,
+            )? {
+                println!("        * Nothing to fold");
+            }
+            Ok(())
+        });
     }
 
-    // Push your work
-    println!("    * Push");
-    branch::push(&branch_name);
+    // Push your work, rebasing and tidying both rewrite what's on the remote
+    {
+        let workspace = workspace.clone();
+        let branch_name = branch_name.clone();
+        if tidy || !ancestry.base_reachable {
+            plan.add(
+                &format!("Force push {} with lease", branch_name),
+                move || branch::force_push_with_lease(&workspace, &branch_name),
+            );
+        } else {
+            plan.add(&format!("Push {}", branch_name), move || {
+                branch::push(&workspace, &branch_name)
+            });
+        }
+    }
+    if stashed {
+        restore_stash(&mut plan, workspace);
+    }
 
     // Remove the wip status
     let reviewers = finished;
@@ -88,8 +331,11 @@ pub fn review(finished: std::vec::Vec<std::string::String>) {
 
         // Verify the changelog has been filled out
         println!("        * Check changelog");
-        if !changelog::verify(&branch_name, Some(&repo_path)) {
-            panic!("You've not filled in your changelog");
+        let changelog_name = branch::changelog_name(&branch_type, &branch_name);
+        if !changelog::verify(workspace, &changelog_name)? {
+            return Err(error::Error::changelog(
+                "You've not filled in your changelog",
+            ));
         }
 
         let mut primary_reviewer = 0_u64;
@@ -98,7 +344,7 @@ pub fn review(finished: std::vec::Vec<std::string::String>) {
         let mut reviewers_names = std::vec::Vec::new();
         for (r_index, r) in reviewers.iter().enumerate() {
             println!("        * Check {} exists", r);
-            let user = server.find_user(&r, true);
+            let user = server.find_user(&r, true)?;
             reviewers_names.push(user.name);
             if r_index == 0 {
                 primary_reviewer = user.id;
@@ -107,36 +353,1138 @@ pub fn review(finished: std::vec::Vec<std::string::String>) {
 
         // Get developer name
         let dev_name = std::env::var("USER")
-            .expect("Unable to find developer name locally");
-        let dev_name = server.find_user(&dev_name, false).name;
+            .config_error("Unable to find developer name locally")?;
+        let dev_name = server.find_user(&dev_name, false)?.name;
 
         // Get changelog
         let changelog = changelog::read_formatted(&changelog::resolve(
-            &branch_name,
-            Some(&repo_path),
-        ));
+            workspace,
+            &changelog_name,
+        ))?;
 
-        // Remove WIP
-        println!("        * Remove WIP");
-        let project = server.project(&remote_url);
-        let [title, summary] = server.final_merge_request(
-            &project,
-            &branch_name,
+        let (merge_request_id, merge_request_url, _) = server
+            .find_merge_request(&project, &branch_name)?
+            .server_error("Unable to find merge request")?;
+        let summary = server::merge_request_summary(
+            &merge_request_url,
             &changelog,
             &reviewers_names,
-            primary_reviewer,
-            &dev_name
+            &dev_name,
         );
 
+        // Remove WIP
+        {
+            let branch_name = branch_name.clone();
+            plan.add(
+                &format!(
+                    "Remove WIP from !{} and assign it to {}",
+                    merge_request_id, reviewers_names[0]
+                ),
+                move || {
+                    server.final_merge_request(
+                        &project,
+                        merge_request_id,
+                        &branch_name,
+                        &changelog,
+                        primary_reviewer,
+                    )
+                },
+            );
+        }
+
         // Send the merge request
-        println!("        * Sending MR to teams");
-        teams::send_mr(&title, &summary);
+        plan.add("Send the merge request to teams", move || {
+            teams::send_mr(&branch_name, &summary)
+        });
+    }
 
-        //println!("\n\n{}", summary);
+    // Only a rebase stops the plan short
+    if !plan.run(dry_run)? && !dry_run {
+        return Err(error::Error::user("Rebase stopped on conflicts")
+            .hint("Review again once it's finished"));
     }
+    Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn rebase(branch_type: branch::Type) {
-    branch::rebase_place_holder(branch_type, &branch::find_name());
+fn current_type(
+    workspace: &workspace::Workspace,
+) -> error::Result<branch::Type> {
+    branch::find_type(workspace)?.ok_or_else(|| {
+        error::Error::user("Unable to determine branch type")
+            .hint("See the types in .git-me.yml")
+    })
+}
+
+//------------------------------------------------------------------------------
+fn verify_nothing_to_commit(
+    workspace: &workspace::Workspace,
+) -> error::Result<()> {
+    println!("    * Check nothing to commit");
+    if !branch::verify_index_empty(workspace)? {
+        return Err(error::Error::user("You have uncommited changes")
+            .hint("Please stash them"));
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Returns true if the changes need stashing, and restoring afterwards.
+fn check_nothing_to_commit(
+    workspace: &workspace::Workspace,
+    autostash: bool,
+) -> error::Result<bool> {
+    println!("    * Check nothing to commit");
+    if branch::verify_index_empty(workspace)? {
+        return Ok(false);
+    }
+
+    if !autostash {
+        return Err(error::Error::user("You have uncommited changes")
+            .hint("Please stash them, or use --autostash"));
+    }
+
+    Ok(true)
+}
+
+//------------------------------------------------------------------------------
+fn stash(plan: &mut plan::Plan, workspace: &workspace::Workspace) {
+    let undo_workspace = workspace.clone();
+    let workspace = workspace.clone();
+    plan.add_with_undo(
+        "Stash changes",
+        move || branch::stash(&workspace),
+        move || {
+            if branch::unstash(&undo_workspace)?.is_some() {
+                println!("They're still in 'git stash list', apply them by hand once you're ready.");
+            }
+            Ok(())
+        },
+    );
+}
+
+//------------------------------------------------------------------------------
+fn restore_stash(plan: &mut plan::Plan, workspace: &workspace::Workspace) {
+    let undo_workspace = workspace.clone();
+    let workspace = workspace.clone();
+    plan.add_with_undo(
+        "Restore stashed changes",
+        move || {
+            if let Some(paths) = branch::unstash(&workspace)? {
+                println!("Your stashed changes don't apply cleanly:");
+                for path in paths.iter() {
+                    println!("    {}", path);
+                }
+                println!("They're kept in 'git stash list', drop them once");
+                println!("you've sorted out the conflicts.");
+            }
+            Ok(())
+        },
+        // Take the changes back with us, if they made it over
+        move || {
+            if !branch::verify_index_empty(&undo_workspace)? {
+                branch::stash(&undo_workspace)?;
+            }
+            Ok(())
+        },
+    );
+}
+
+//------------------------------------------------------------------------------
+pub fn finish(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    dry_run: bool,
+) -> error::Result<()> {
+    let branch_name = branch::find_name(workspace)?;
+    if branch::find_type(workspace)?.as_ref() != Some(branch_type) {
+        return Err(error::Error::user(&format!(
+            "You are on the {} branch, which isn't a {} branch",
+            branch_name, branch_type.name
+        )));
+    }
+
+    // Verify there's nothing in the index
+    verify_nothing_to_commit(workspace)?;
+
+    // Find the merge request
+    println!("    * Check merge request");
+    let server = std::rc::Rc::new(server::Server::new()?);
+    let project = server.project(workspace)?;
+    let target = branch::target_of(workspace, branch_type, &branch_name)?;
+    let stacked_on = branch::stacked_on(workspace, &branch_name)?;
+
+    // Stacked branches land in their parent, which takes care of merging back
+    let back_merge = match stacked_on {
+        Some(_) => None,
+        None => branch::back_merge(branch_type).map(str::to_string),
+    };
+    let (merge_request_id, merge_request_url, merge_request_state) = server
+        .find_merge_request_into(&project, &branch_name, Some(&target))?
+        .ok_or_else(|| {
+            error::Error::user(&format!(
+                "Unable to find a merge request for {}",
+                branch_name
+            ))
+        })?;
+
+    let mut plan = plan::Plan::new();
+
+    use gitlab::types::MergeRequestState;
+    match merge_request_state {
+        MergeRequestState::Merged => println!("        * Already merged"),
+        MergeRequestState::Opened | MergeRequestState::Reopened => {
+            println!("        * Check approved");
+            if !server.approved(&project, merge_request_id)? {
+                return Err(error::Error::user(&format!(
+                    "{} hasn't been approved yet",
+                    merge_request_url
+                )));
+            }
+
+            // Anything stacked on top of us needs to land in our target now,
+            // before gitlab removes our branch from under it
+            for child in
+                server.find_open_merge_requests_into(&project, &branch_name)?
+            {
+                let server = server.clone();
+                let project = project.clone();
+                let target = target.clone();
+                plan.add(
+                    &format!(
+                        "Retarget !{} {} to {}",
+                        child.iid, child.source_branch, target
+                    ),
+                    move || {
+                        server.retarget_merge_request(
+                            &project, child.iid, &target,
+                        )
+                    },
+                );
+            }
+
+            let server = server.clone();
+            let project = project.clone();
+            let branch_name = branch_name.clone();
+            let target = target.clone();
+            let remove_source_branch = back_merge.is_none();
+            plan.add(&format!("Merge {}", merge_request_url), move || {
+                server.accept_merge_request(
+                    &project,
+                    merge_request_id,
+                    remove_source_branch,
+                )?;
+                wait_for_merge(&server, &project, &branch_name, &target)
+            });
+        }
+        _ => {
+            return Err(error::Error::user(&format!(
+                "{} has been closed",
+                merge_request_url
+            )))
+        }
+    }
+
+    // Merge the work back, the back-merge request removes the remote branch
+    // once it lands
+    if let Some(back_merge) = back_merge.as_deref() {
+        match server.find_merge_request_into(
+            &project,
+            &branch_name,
+            Some(back_merge),
+        )? {
+            Some((_, url, _)) => {
+                println!("    * Back-merge into {}", back_merge);
+                println!("        * {}", url);
+            }
+            None => {
+                let server = server.clone();
+                let project = project.clone();
+                let branch_name = branch_name.clone();
+                let back_merge = back_merge.to_string();
+                plan.add(
+                    &format!("Create back-merge request into {}", back_merge),
+                    move || {
+                        let url = server.back_merge_request(
+                            &project,
+                            &back_merge,
+                            &branch_name,
+                        )?;
+                        println!("        * {}", url);
+                        Ok(())
+                    },
+                );
+            }
+        }
+    }
+
+    // Bring the target up to date so it includes the merge
+    {
+        let workspace = workspace.clone();
+        let target = target.clone();
+        plan.add(&format!("Fast forward {}", target), move || {
+            // Releases fork from develop, so master may not be local
+            if !branch::exists(&workspace, &target)? {
+                branch::track(&workspace, &target)?;
+            }
+            branch::fast_forward(&workspace, &target)?;
+            branch::switch(&workspace, &target)
+        });
+    }
+
+    // Releases are tagged once they land
+    if branch_type.release {
+        let version = branch_name
+            .trim_start_matches(&branch_type.prefix)
+            .to_string();
+        let workspace = workspace.clone();
+        plan.add(&format!("Tag {}", version), move || {
+            branch::tag(&workspace, &version)
+        });
+    }
+
+    // Clean up
+    let old_tip = branch::tip(workspace, &branch_name)?;
+    {
+        let workspace = workspace.clone();
+        let branch_name = branch_name.clone();
+        plan.add(&format!("Delete {}", branch_name), move || {
+            branch::delete(&workspace, &branch_name)
+        });
+    }
+    if back_merge.is_none() {
+        // Gitlab has usually removed it already as part of the merge
+        let workspace = workspace.clone();
+        let branch_name = branch_name.clone();
+        plan.add(
+            &format!(
+                "Delete {}",
+                branch::remote_branch(&workspace, &branch_name)?
+            ),
+            move || {
+                if branch::remote_branch_exists(&workspace, &branch_name)? {
+                    branch::delete_remote(&workspace, &branch_name)?;
+                }
+                Ok(())
+            },
+        );
+    }
+
+    // Move our local stacked branches onto where we landed
+    for child in branch::stacked_children(workspace, &branch_name)? {
+        let workspace = workspace.clone();
+        let branch_type = branch_type.clone();
+        let stacked_on = stacked_on.clone();
+        let target = target.clone();
+        plan.add_step(
+            &format!("Restack {} onto {} and force push it", child, target),
+            move || {
+                branch::stack_on(&workspace, &child, stacked_on.as_deref())?;
+                match branch::restack(&workspace, &target, old_tip, &child)? {
+                    branch::RebaseOutcome::Finished => {
+                        branch::force_push(&workspace, &child)?;
+                        Ok(plan::Outcome::Continue)
+                    }
+                    branch::RebaseOutcome::Conflicts(paths) => {
+                        report_conflicts(&branch_type, &paths);
+                        Ok(plan::Outcome::Stop)
+                    }
+                }
+            },
+        );
+    }
+    {
+        let workspace = workspace.clone();
+        plan.add(&format!("Switch to {}", target), move || {
+            branch::switch(&workspace, &target)
+        });
+    }
+
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+fn wait_for_merge(
+    server: &server::Server,
+    project: &server::Project,
+    branch_name: &str,
+    target: &str,
+) -> error::Result<()> {
+    // Gitlab can take a moment to actually merge once it has accepted
+    for _ in 0..30 {
+        if let Some((_, _, gitlab::types::MergeRequestState::Merged)) = server
+            .find_merge_request_into(
+            project,
+            branch_name,
+            Some(target),
+        )? {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_secs(2));
+    }
+
+    Err(error::Error::server(&format!(
+        "Timed out waiting for {} to merge",
+        branch_name
+    )))
+}
+
+//------------------------------------------------------------------------------
+pub fn list(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    all: bool,
+) -> error::Result<()> {
+    let types = if all {
+        workflow::Workflow::open(workspace)?
+            .types
+            .into_values()
+            .collect()
+    } else {
+        vec![branch_type.clone()]
+    };
+
+    let server = server::Server::new()?;
+    let project = server.project(workspace)?;
+    let current = branch::find_name(workspace)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    for branch_type in types.iter() {
+        for listing in branch::list(workspace, &branch_type.prefix)? {
+            // Stacked branches and backports land somewhere else, hotfixes
+            // also have a back-merge from the same branch
+            let target =
+                branch::target_of(workspace, branch_type, &listing.name)?;
+            let merge_request = match server.find_merge_request_details(
+                &project,
+                &listing.name,
+                Some(&target),
+            )? {
+                Some(mr) if mr.work_in_progress => {
+                    format!("!{} WIP {:?}", mr.iid, mr.state)
+                }
+                Some(mr) => format!("!{} {:?}", mr.iid, mr.state),
+                None => "no MR".to_string(),
+            };
+
+            let days = (now - listing.last_commit.seconds()) / (60 * 60 * 24);
+            println!(
+                "{} {:<40} {:<6} {:<16} {} days ago",
+                if listing.name == current { "*" } else { " " },
+                listing.name,
+                match (listing.local, listing.remote) {
+                    (true, true) => "both",
+                    (true, false) => "local",
+                    _ => "remote",
+                },
+                merge_request,
+                days
+            );
+        }
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+pub fn switch(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    name: &str,
+    dry_run: bool,
+) -> error::Result<()> {
+    let branch_name = branch::resolve(branch_type, name);
+
+    // Verify there's nothing in the index
+    verify_nothing_to_commit(workspace)?;
+
+    let mut plan = plan::Plan::new();
+    if !branch::exists(workspace, &branch_name)? {
+        if !branch::remote_branch_exists(workspace, &branch_name)? {
+            return Err(error::Error::user(&format!(
+                "There's no {} here or on the remote",
+                branch_name
+            )));
+        }
+        let workspace = workspace.clone();
+        let branch_name = branch_name.clone();
+        plan.add(&format!("Fetch {}", branch_name), move || {
+            branch::track(&workspace, &branch_name)
+        });
+    }
+
+    let workspace = workspace.clone();
+    plan.add(&format!("Switch to {}", branch_name), move || {
+        branch::switch(&workspace, &branch_name)
+    });
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Rename the current branch, and move its changelog, remote branch and merge
+/// request over to the new name.
+pub fn rename(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    name: &str,
+    dry_run: bool,
+) -> error::Result<()> {
+    let branch_name = branch::find_name(workspace)?;
+    if current_type(workspace)?.name != branch_type.name {
+        return Err(error::Error::user(&format!(
+            "{} isn't a {} branch",
+            branch_name, branch_type.name
+        )));
+    }
+
+    // Verify there's nothing in the index
+    verify_nothing_to_commit(workspace)?;
+
+    // Verify the new name is well formed, and free
+    println!("    * Check name '{}' is well formed", name);
+    if !branch::well_formed_for(branch_type, name)? {
+        return Err(error::Error::user(&format!(
+            "Your branch name has invalid characters in it '{}'",
+            name
+        ))
+        .hint(&format!("It needs to match {}", branch_type.naming)));
+    }
+    let new_name = branch::resolve(branch_type, name);
+    if new_name == branch_name {
+        return Err(error::Error::user(&format!(
+            "It's already called {}",
+            new_name
+        )));
+    }
+    if branch::exists(workspace, &new_name)?
+        || branch::remote_branch_exists(workspace, &new_name)?
+    {
+        return Err(error::Error::user(&format!(
+            "There's already a {}",
+            new_name
+        )));
+    }
+
+    println!("    * Check merge request");
+    let server = std::rc::Rc::new(server::Server::new()?);
+    let project = server.project(workspace)?;
+    use gitlab::types::MergeRequestState;
+    let merge_request = match server.find_merge_request_details(
+        &project,
+        &branch_name,
+        None,
+    )? {
+        Some(mr) if mr.state == MergeRequestState::Merged => {
+            return Err(error::Error::user(&format!(
+                "{} has already been merged",
+                mr.web_url
+            )))
+        }
+        Some(mr) if mr.state == MergeRequestState::Opened => Some(mr),
+        _ => None,
+    };
+    let stacked_on = branch::stacked_on(workspace, &branch_name)?;
+    let old_tip = branch::tip(workspace, &branch_name)?;
+
+    let mut plan = plan::Plan::new();
+
+    // Rename the branch, and its changelog along with it
+    {
+        let branch_name = branch_name.clone();
+        let new_name = new_name.clone();
+        let undo_branch_name = branch_name.clone();
+        let undo_new_name = new_name.clone();
+        let undo_workspace = workspace.clone();
+        let workspace = workspace.clone();
+        plan.add_with_undo(
+            &format!("Rename {} to {}", branch_name, new_name),
+            move || branch::rename(&workspace, &branch_name, &new_name),
+            move || {
+                branch::rename(
+                    &undo_workspace,
+                    &undo_new_name,
+                    &undo_branch_name,
+                )
+            },
+        );
+    }
+    let changelog_name = branch::changelog_name(branch_type, &branch_name);
+    if changelog::resolve(workspace, &changelog_name).exists() {
+        let new_changelog_name = branch::changelog_name(branch_type, &new_name);
+        let undo_workspace = workspace.clone();
+        let workspace = workspace.clone();
+        plan.add_with_undo(
+            &format!("Move its changelog to {}", new_changelog_name),
+            move || {
+                changelog::rename(
+                    &workspace,
+                    &changelog_name,
+                    &new_changelog_name,
+                )
+            },
+            move || branch::reset_hard(&undo_workspace, old_tip),
+        );
+    }
+
+    // Push the new branch
+    {
+        let new_name = new_name.clone();
+        let undo_new_name = new_name.clone();
+        let undo_workspace = workspace.clone();
+        let workspace = workspace.clone();
+        plan.add_with_undo(
+            &format!("Push {}", new_name),
+            move || {
+                branch::push(&workspace, &new_name)?;
+                branch::set_upstream(&workspace, &new_name)
+            },
+            move || branch::delete_remote(&undo_workspace, &undo_new_name),
+        );
+    }
+
+    // Gitlab can't change the branch of a merge request, so open a new one
+    // and close the old one
+    if let Some(merge_request) = merge_request {
+        let undo_server = server.clone();
+        let undo_project = project.clone();
+        let undo_new_name = new_name.clone();
+        let iid = merge_request.iid;
+        let server = server.clone();
+        let project = project.clone();
+        let new_name = new_name.clone();
+        // Keep the branch around if it still needs merging back
+        let remove_source_branch =
+            stacked_on.is_some() || branch::back_merge(branch_type).is_none();
+        plan.add_with_undo(
+            &format!("Move !{} over to {}", merge_request.iid, new_name),
+            move || {
+                let new_merge_request = server.recreate_merge_request(
+                    &project,
+                    &merge_request,
+                    &new_name,
+                    remove_source_branch,
+                )?;
+                println!("        * {}", new_merge_request.web_url);
+                let closed = server.close_merge_request(
+                    &project,
+                    merge_request.iid,
+                    &format!(
+                        "Renamed to {}, carries on in !{}",
+                        new_name, new_merge_request.iid
+                    ),
+                );
+
+                // Don't leave both open, this step won't be undone
+                if closed.is_err() {
+                    server.close_merge_request(
+                        &project,
+                        new_merge_request.iid,
+                        &format!(
+                            "The rename was undone, back to !{}",
+                            merge_request.iid
+                        ),
+                    )?;
+                }
+                closed
+            },
+            move || {
+                if let Some((new_iid, _, _)) = undo_server
+                    .find_merge_request(&undo_project, &undo_new_name)?
+                {
+                    undo_server.close_merge_request(
+                        &undo_project,
+                        new_iid,
+                        &format!("The rename was undone, back to !{}", iid),
+                    )?;
+                }
+                undo_server.reopen_merge_request(&undo_project, iid)
+            },
+        );
+    }
+
+    // Bring the branches stacked on this one over too
+    for child in branch::stacked_children(workspace, &branch_name)? {
+        let child_merge_request = server.find_merge_request_into(
+            &project,
+            &child,
+            Some(&branch_name),
+        )?;
+        let workspace = workspace.clone();
+        let server = server.clone();
+        let project = project.clone();
+        let new_name = new_name.clone();
+        plan.add(&format!("Stack {} on {}", child, new_name), move || {
+            branch::stack_on(&workspace, &child, Some(&new_name))?;
+            match child_merge_request {
+                Some((iid, _, MergeRequestState::Opened)) => {
+                    server.retarget_merge_request(&project, iid, &new_name)
+                }
+                _ => Ok(()),
+            }
+        });
+    }
+
+    // Remove the old branch from the remote
+    if branch::remote_branch_exists(workspace, &branch_name)? {
+        let workspace = workspace.clone();
+        plan.add(
+            &format!(
+                "Delete {}",
+                branch::remote_branch(&workspace, &branch_name)?
+            ),
+            move || branch::delete_remote(&workspace, &branch_name),
+        );
+    }
+
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Give up on the current branch, closing its merge request and deleting it
+/// here and on the remote.
+pub fn abandon(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    comment: Option<&str>,
+    yes: bool,
+    dry_run: bool,
+) -> error::Result<()> {
+    let branch_name = branch::find_name(workspace)?;
+    if current_type(workspace)?.name != branch_type.name {
+        return Err(error::Error::user(&format!(
+            "{} isn't a {} branch",
+            branch_name, branch_type.name
+        )));
+    }
+
+    // Verify there's nothing in the index
+    verify_nothing_to_commit(workspace)?;
+
+    // Anything stacked on top would carry our changelog into the base
+    let children = branch::stacked_children(workspace, &branch_name)?;
+    if !children.is_empty() {
+        return Err(error::Error::user(&format!(
+            "{} is stacked on {}",
+            children.join(", "),
+            branch_name
+        ))
+        .hint("Abandon them first, or rebase them onto the base"));
+    }
+
+    let base = branch::base_of(workspace, branch_type, &branch_name)?;
+    let ancestry = branch::ancestry(workspace, &branch_name, &base)?;
+    println!("    * {} commits that aren't in {}", ancestry.ahead, base);
+
+    println!("    * Check merge request");
+    let server = server::Server::new()?;
+    let project = server.project(workspace)?;
+    let merge_request = server.find_merge_request(&project, &branch_name)?;
+    if let Some((_, url, _)) = &merge_request {
+        println!("        * {}", url);
+    }
+
+    if !(yes
+        || dry_run
+        || super::confirm(&format!("Abandon {}?", branch_name))?)
+    {
+        return Ok(());
+    }
+
+    let mut plan = plan::Plan::new();
+    if let Some((iid, _, gitlab::types::MergeRequestState::Opened)) =
+        merge_request
+    {
+        let comment = comment.unwrap_or("Abandoned").to_string();
+        plan.add(&format!("Close !{}", iid), move || {
+            server.close_merge_request(&project, iid, &comment)
+        });
+    }
+
+    // Go back to where the branch came from
+    if !branch::exists(workspace, &base)? {
+        let workspace = workspace.clone();
+        let base = base.clone();
+        plan.add(&format!("Fetch {}", base), move || {
+            branch::track(&workspace, &base)
+        });
+    }
+    {
+        let workspace = workspace.clone();
+        plan.add(&format!("Switch to {}", base), move || {
+            branch::switch(&workspace, &base)
+        });
+    }
+
+    // Delete the branch, and its changelog goes with it
+    if branch::remote_branch_exists(workspace, &branch_name)? {
+        let workspace = workspace.clone();
+        let branch_name = branch_name.clone();
+        plan.add(
+            &format!(
+                "Delete {}",
+                branch::remote_branch(&workspace, &branch_name)?
+            ),
+            move || branch::delete_remote(&workspace, &branch_name),
+        );
+    }
+    {
+        let workspace = workspace.clone();
+        plan.add(&format!("Delete {}", branch_name), move || {
+            branch::delete(&workspace, &branch_name)
+        });
+    }
+
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Pick up a branch someone else started, by its name or its merge request.
+pub fn checkout(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    name: &str,
+    dry_run: bool,
+) -> error::Result<()> {
+    // Verify there's nothing in the index
+    verify_nothing_to_commit(workspace)?;
+
+    // A number, ie !12, is a merge request
+    let branch_name = match name.trim_start_matches('!').parse::<u64>() {
+        Ok(iid) => {
+            println!("    * Find merge request !{}", iid);
+            let server = server::Server::new()?;
+            let project = server.project(workspace)?;
+            let merge_request =
+                server.find_merge_request_by_iid(&project, iid)?;
+            if !merge_request.source_branch.starts_with(&branch_type.prefix) {
+                return Err(error::Error::user(&format!(
+                    "!{} is from {}, which isn't a {} branch",
+                    iid, merge_request.source_branch, branch_type.name
+                )));
+            }
+            merge_request.source_branch
+        }
+        Err(_) if name.starts_with(&branch_type.prefix) => name.to_string(),
+        Err(_) => branch::resolve(branch_type, name),
+    };
+
+    let mut plan = plan::Plan::new();
+    if branch::exists(workspace, &branch_name)? {
+        println!("    * {} is already here", branch_name);
+    } else {
+        if !branch::remote_branch_exists(workspace, &branch_name)? {
+            return Err(error::Error::user(&format!(
+                "There's no {} on the remote",
+                branch_name
+            )));
+        }
+        let workspace = workspace.clone();
+        let branch_name = branch_name.clone();
+        plan.add(
+            &format!(
+                "Fetch {} and track it",
+                branch::remote_branch(&workspace, &branch_name)?
+            ),
+            move || branch::track(&workspace, &branch_name),
+        );
+    }
+    {
+        let workspace = workspace.clone();
+        let branch_name = branch_name.clone();
+        plan.add(&format!("Switch to {}", branch_name), move || {
+            branch::switch(&workspace, &branch_name)
+        });
+    }
+
+    // It'll need one before it can be reviewed
+    {
+        let changelog_name = branch::changelog_name(branch_type, &branch_name);
+        let workspace = workspace.clone();
+        plan.add("Check the changelog", move || {
+            if !changelog::resolve(&workspace, &changelog_name).exists() {
+                println!("        * {} has no changelog", branch_name);
+                println!("          Make one with 'git changelog edit'");
+            }
+            Ok(())
+        });
+    }
+
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+/// Copy the current branch onto each of the targets, ie the release branches
+/// that are still maintained, with a merge request into each. A target that
+/// doesn't work out is reported, and the rest carry on.
+pub fn backport(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    targets: &[std::string::String],
+    dry_run: bool,
+) -> error::Result<()> {
+    let branch_name = branch::find_name(workspace)?;
+    if current_type(workspace)?.name != branch_type.name {
+        return Err(error::Error::user(&format!(
+            "{} isn't a {} branch",
+            branch_name, branch_type.name
+        )));
+    }
+    if targets.is_empty() {
+        return Err(error::Error::user("Nothing to backport onto")
+            .hint("Give the branches with --to, ie --to release/1.4"));
+    }
+
+    let server = std::rc::Rc::new(server::Server::new()?);
+    let project = server.project(workspace)?;
+
+    let mut failed = std::vec::Vec::new();
+    for target in targets.iter() {
+        println!("    * Backport onto {}", target);
+        let result = backport_onto(
+            workspace,
+            branch_type,
+            &branch_name,
+            target,
+            &server,
+            &project,
+            dry_run,
+        );
+        match result {
+            Ok(_) => (),
+            Err(error) => {
+                println!("        * {}", error);
+                failed.push(target.as_str());
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(error::Error::user(&format!(
+            "Unable to backport onto {}",
+            failed.join(", ")
+        ))
+        .hint("Backport those by hand with 'git cherry-pick -x'"));
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+fn backport_onto(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    branch_name: &str,
+    target: &str,
+    server: &std::rc::Rc<server::Server>,
+    project: &server::Project,
+    dry_run: bool,
+) -> error::Result<bool> {
+    // hotfix/crash onto release/1.4 is hotfix/crash-1.4
+    let version = target.rsplit('/').next().unwrap_or(target);
+    let new_name = format!("{}-{}", branch_name, version);
+    if !branch::remote_branch_exists(workspace, target)? {
+        return Err(error::Error::user(&format!(
+            "There's no {} on the remote",
+            target
+        )));
+    }
+    if branch::exists(workspace, &new_name)?
+        || branch::remote_branch_exists(workspace, &new_name)?
+    {
+        return Err(error::Error::user(&format!(
+            "There's already a {}",
+            new_name
+        )));
+    }
+    let base = branch::base_of(workspace, branch_type, branch_name)?;
+
+    let mut plan = plan::Plan::new();
+
+    // Pick the commits onto the target, without touching the checkout
+    {
+        let workspace = workspace.clone();
+        let branch_type = branch_type.clone();
+        let branch_name = branch_name.to_string();
+        let target = target.to_string();
+        let new_name = new_name.clone();
+        let undo_workspace = workspace.clone();
+        let undo_new_name = new_name.clone();
+        plan.add_with_undo(
+            &format!("Pick {} onto {} as {}", branch_name, target, new_name),
+            move || match branch::backport(
+                &workspace,
+                &branch_type,
+                &branch_name,
+                &base,
+                &target,
+                &new_name,
+            )? {
+                history::PickOutcome::Picked(_) => Ok(()),
+                history::PickOutcome::Conflicts(commit, paths) => {
+                    println!("        * {} conflicts in", commit);
+                    for path in paths.iter() {
+                        println!("            {}", path);
+                    }
+                    Err(error::Error::user(&format!(
+                        "{} doesn't pick cleanly onto {}",
+                        branch_name, target
+                    )))
+                }
+            },
+            move || branch::delete(&undo_workspace, &undo_new_name),
+        );
+    }
+
+    // It forks from and lands in the target, not the hotfix's base
+    {
+        let workspace = workspace.clone();
+        let target = target.to_string();
+        let new_name = new_name.clone();
+        let undo_workspace = workspace.clone();
+        let undo_new_name = new_name.clone();
+        plan.add_with_undo(
+            &format!("Stack {} on {}", new_name, target),
+            move || branch::stack_on(&workspace, &new_name, Some(&target)),
+            move || branch::stack_on(&undo_workspace, &undo_new_name, None),
+        );
+    }
+
+    // Push it
+    {
+        let new_name = new_name.clone();
+        let undo_new_name = new_name.clone();
+        let undo_workspace = workspace.clone();
+        let workspace = workspace.clone();
+        plan.add_with_undo(
+            &format!("Push {}", new_name),
+            move || {
+                branch::push(&workspace, &new_name)?;
+                branch::set_upstream(&workspace, &new_name)
+            },
+            move || branch::delete_remote(&undo_workspace, &undo_new_name),
+        );
+    }
+
+    // Create a merge request into the target
+    {
+        let server = server.clone();
+        let project = project.clone();
+        let target = target.to_string();
+        let description =
+            format!("Backport of {} onto {}", branch_name, target);
+        plan.add(
+            &format!("Create merge request {} into {}", new_name, target),
+            move || {
+                server.merge_request(
+                    &project,
+                    &target,
+                    &new_name,
+                    true,
+                    Some(&description),
+                )
+            },
+        );
+    }
+
+    plan.run(dry_run)
+}
+
+//------------------------------------------------------------------------------
+fn report_conflicts(branch_type: &branch::Type, paths: &[std::string::String]) {
+    println!("    * Conflicts");
+    for path in paths.iter() {
+        println!("        {}", path);
+    }
+    println!("Resolve the conflicts, 'git add' them, then run:");
+    println!("    git me {} rebase --continue", branch_type.name);
+    println!("or give up with:");
+    println!("    git me {} rebase --abort", branch_type.name);
+}
+
+//------------------------------------------------------------------------------
+pub fn rebase(
+    workspace: &workspace::Workspace,
+    branch_type: &branch::Type,
+    continue_: bool,
+    abort: bool,
+    dry_run: bool,
+) -> error::Result<()> {
+    let mut plan = plan::Plan::new();
+    if abort {
+        let workspace = workspace.clone();
+        plan.add("Abort rebase", move || branch::rebase_abort(&workspace));
+        plan.run(dry_run)?;
+        return Ok(());
+    }
+
+    // Only rewrite the remote once the rebase has succeeded
+    let rebased = {
+        let branch_type = branch_type.clone();
+        move |outcome| match outcome {
+            branch::RebaseOutcome::Finished => Ok(plan::Outcome::Continue),
+            branch::RebaseOutcome::Conflicts(paths) => {
+                report_conflicts(&branch_type, &paths);
+                Ok(plan::Outcome::Stop)
+            }
+        }
+    };
+
+    if continue_ {
+        {
+            let workspace = workspace.clone();
+            plan.add_step("Continue rebase", move || {
+                rebased(branch::rebase_continue(&workspace)?)
+            });
+        }
+        let workspace = workspace.clone();
+        plan.add("Force push", move || {
+            branch::force_push(&workspace, &branch::find_name(&workspace)?)
+        });
+    } else {
+        // Verify there's nothing in the index
+        verify_nothing_to_commit(workspace)?;
+
+        let branch_name = branch::find_name(workspace)?;
+        let mut base = branch::base_of(workspace, branch_type, &branch_name)?;
+
+        // The branch we were stacked on has landed without us, so follow it
+        if let Some(parent) = branch::stacked_on(workspace, &branch_name)? {
+            if !branch::remote_branch_exists(workspace, &parent)? {
+                base = branch::base(branch_type).to_string();
+                println!("    * {} has landed", parent);
+                let workspace = workspace.clone();
+                let branch_name = branch_name.clone();
+                plan.add(
+                    &format!("Unstack {} from {}", branch_name, parent),
+                    move || branch::stack_on(&workspace, &branch_name, None),
+                );
+            }
+        }
+
+        {
+            let workspace = workspace.clone();
+            let branch_name = branch_name.clone();
+            plan.add_step(
+                &format!("Rebase {} onto {}", branch_name, base),
+                move || {
+                    rebased(branch::rebase(&workspace, &base, &branch_name)?)
+                },
+            );
+        }
+        let workspace = workspace.clone();
+        plan.add(&format!("Force push {}", branch_name), move || {
+            branch::force_push(&workspace, &branch_name)
+        });
+    }
+
+    if !plan.run(dry_run)? && !dry_run {
+        return Err(error::Error::user("Rebase stopped on conflicts"));
+    }
+    Ok(())
 }
@@ -0,0 +1,65 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::branch;
+use crate::error;
+use crate::plan;
+use crate::workspace;
+
+//------------------------------------------------------------------------------
+pub fn list(workspace: &workspace::Workspace) -> error::Result<()> {
+    for worktree in branch::worktrees(workspace)? {
+        println!(
+            "{:<30} {:<40} {}",
+            worktree.name,
+            worktree.branch.as_deref().unwrap_or("(detached)"),
+            worktree.path.display()
+        );
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+pub fn remove(
+    workspace: &workspace::Workspace,
+    name: &str,
+    force: bool,
+    dry_run: bool,
+) -> error::Result<()> {
+    // Accept either the worktree's name, or the name it was started with
+    let worktrees = branch::worktrees(workspace)?;
+    let path = branch::worktree_path(workspace, name)?;
+    let worktree = worktrees
+        .iter()
+        .find(|w| w.name == name)
+        .or_else(|| worktrees.iter().find(|w| same_path(&w.path, &path)))
+        .ok_or_else(|| {
+            error::Error::user(&format!("Unable to find worktree {}", name))
+                .hint("See 'git me worktree list'")
+        })?;
+
+    if same_path(&worktree.path, workspace.path()) {
+        return Err(error::Error::user(
+            "You can't remove the worktree you're in",
+        ));
+    }
+
+    let mut plan = plan::Plan::new();
+    {
+        let workspace = workspace.clone();
+        let name = worktree.name.clone();
+        plan.add(&format!("Remove {}", worktree.path.display()), move || {
+            branch::remove_worktree(&workspace, &name, force)
+        });
+    }
+    plan.run(dry_run)?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+fn same_path(lhs: &std::path::Path, rhs: &std::path::Path) -> bool {
+    match (lhs.canonicalize(), rhs.canonicalize()) {
+        (Ok(lhs), Ok(rhs)) => lhs == rhs,
+        _ => lhs == rhs,
+    }
+}
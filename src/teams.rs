@@ -1,6 +1,8 @@
+use crate::error;
+use crate::error::Context as _;
 use std::collections::HashMap;
 
-pub fn send_mr(title: &str, message: &str) {
+pub fn send_mr(title: &str, message: &str) -> error::Result<()> {
     let webhook = "";
 
     let mut body = HashMap::new();
@@ -13,5 +15,6 @@ pub fn send_mr(title: &str, message: &str) {
         .json(&body)
         .header("Content-Type", "application/json")
         .send()
-        .expect("Failed to post MR to teams");
+        .server_error("Failed to post MR to teams")?;
+    Ok(())
 }
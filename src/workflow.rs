@@ -0,0 +1,137 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::branch;
+use crate::error;
+use crate::error::Context as _;
+use crate::lint;
+use crate::workspace;
+
+const WORKFLOW: &str = ".git-me.yml";
+
+/// The workflow for a repo. This lives in a .git-me.yml at the root of the
+/// repo, which is committed along with everything else. For example:
+///
+/// types:
+///   feature:
+///     base: main
+///   bugfix:
+///     prefix: bugfix/
+///     base: main
+///     changelog: changelog/template.yml
+/// commits:
+///   max_subject: 72
+///
+/// Types given in the file replace the built in ones with the same name. See
+/// lint.rs for the commit message rules.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Workflow {
+    #[serde(default)]
+    pub types: std::collections::BTreeMap<std::string::String, branch::Type>,
+    #[serde(default)]
+    pub commits: lint::Rules,
+}
+
+impl Default for Workflow {
+    fn default() -> Self {
+        let type_ = |base: &str, target: &str, back_merge: Option<&str>| {
+            branch::Type {
+                name: std::string::String::new(),
+                prefix: std::string::String::new(),
+                base: base.to_string(),
+                target: Some(target.to_string()),
+                back_merge: back_merge.map(|b| b.to_string()),
+                naming: branch::NAMING.to_string(),
+                changelog: None,
+                release: false,
+            }
+        };
+
+        let mut release = type_("develop", "master", Some("develop"));
+        release.naming = r"^v?[0-9]+(\.[0-9]+)*$".to_string();
+        release.release = true;
+
+        let mut types = std::collections::BTreeMap::new();
+        types.insert("feature".to_string(), type_("develop", "develop", None));
+        types.insert(
+            "hotfix".to_string(),
+            type_("master", "master", Some("develop")),
+        );
+        types.insert("release".to_string(), release);
+
+        Self {
+            types,
+            commits: lint::Rules::default(),
+        }
+    }
+}
+
+impl Workflow {
+    pub fn file_path(workspace: &workspace::Workspace) -> std::path::PathBuf {
+        workspace.path().join(WORKFLOW)
+    }
+
+    pub fn open(workspace: &workspace::Workspace) -> error::Result<Self> {
+        let mut workflow = Self::default();
+
+        let file_path = Self::file_path(workspace);
+        if file_path.exists() {
+            let repo_workflow: Workflow = serde_yaml::from_reader(
+                std::fs::File::open(&file_path)
+                    .config_error("Unable to open the workflow file")?,
+            )
+            .config_error(&format!("Unable to read {}", file_path.display()))?;
+            workflow.types.extend(repo_workflow.types);
+            workflow.commits = repo_workflow.commits;
+        }
+
+        // Fill in what can be derived from the name
+        for (name, type_) in workflow.types.iter_mut() {
+            type_.name = name.clone();
+            if type_.prefix.is_empty() {
+                type_.prefix = format!("{}/", name);
+            }
+        }
+
+        Ok(workflow)
+    }
+
+    pub fn find(&self, name: &str) -> error::Result<branch::Type> {
+        if let Some(type_) = self.types.get(name) {
+            return Ok(type_.clone());
+        }
+
+        let mut hint = "Types are:\n".to_string();
+        for name in self.types.keys() {
+            hint.push_str(&format!("    {}\n", name));
+        }
+        hint.push_str(&format!("Add '{}' to the types in {}", name, WORKFLOW));
+
+        Err(error::Error::config(&format!(
+            "Unable to find branch type '{}'",
+            name
+        ))
+        .hint(&hint))
+    }
+
+    pub fn find_by_branch(&self, branch_name: &str) -> Option<branch::Type> {
+        // Prefer the longest prefix, so 'feature/ui/' wins over 'feature/'
+        self.types
+            .values()
+            .filter(|t| {
+                branch_name.len() > t.prefix.len()
+                    && branch_name.starts_with(&t.prefix)
+            })
+            .max_by_key(|t| t.prefix.len())
+            .cloned()
+    }
+
+    /// The prefixes of the branches that have their own changelog
+    pub fn changelog_prefixes(&self) -> std::vec::Vec<std::string::String> {
+        self.types
+            .values()
+            .filter(|t| !t.release)
+            .map(|t| t.prefix.clone())
+            .collect()
+    }
+}
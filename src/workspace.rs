@@ -0,0 +1,45 @@
+//------------------------------------------------------------------------------
+// from+git_me@luketitley.com
+//------------------------------------------------------------------------------
+use crate::error;
+use crate::error::Context as _;
+
+//------------------------------------------------------------------------------
+/// A checkout of a git repo, either the main one or a linked worktree.
+///
+/// Everything that reads or changes a repo is handed one of these, rather
+/// than going by the current directory, so a single process can work on as
+/// many repos as it likes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workspace {
+    path: std::path::PathBuf,
+}
+
+impl Workspace {
+    /// The checkout that path is somewhere inside of.
+    pub fn discover(path: &std::path::Path) -> error::Result<Self> {
+        let repo = git2::Repository::discover(path).git_error(&format!(
+            "Unable to find a git repo at {}",
+            path.display()
+        ))?;
+        let path = repo
+            .workdir()
+            .git_error("Bare repos don't have a checkout to work in")?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// The top of the checkout.
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    pub fn open(&self) -> error::Result<git2::Repository> {
+        git2::Repository::open(&self.path).git_error(&format!(
+            "Unable to open the git repo at {}",
+            self.path.display()
+        ))
+    }
+}